@@ -1,12 +1,18 @@
-use std::collections::HashMap;
+// messages are written `println!("{}text{}\x1b[0m", color, .., "")`: the trailing "" keeps
+// every colored line the same shape so a color can be slotted in later without reshuffling
+// args. clippy flags that literal on each of the ~240 call sites, so allow it once here.
+#![allow(clippy::print_literal)]
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::fs::{self, File, OpenOptions, Metadata};
-use std::io::{self, BufRead, Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use atty::Stream;
-use term_size;
+use regex::RegexBuilder;
 
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
@@ -17,6 +23,29 @@ const APP_NAME: &str = "trust";
 const APP_VERSION: &str = "trust v0.1.0 🦀";
 
 const UNDO_MAX: usize = 200;
+// same-command edits closer together than this share one undo snapshot
+const UNDO_COALESCE: Duration = Duration::from_secs(1);
+
+// commands that change the buffer; refused while it is read-only
+const EDIT_COMMANDS: &[&str] = &[
+    "append", "a", "insert", "i", "delete", "d", "reflow", "expand", "unexpand", "trim",
+    "json-fmt", "rustfmt", "rs-snip", "undo", "u", "redo", "recover", "pipe",
+    "readcmd", "revert",
+];
+
+const THEME_NAMES: &[&str] = &[
+    "default", "dark", "neon", "matrix", "paper", "solarized", "gruvbox", "nord",
+];
+
+// keys `set` understands, in the order show_settings lists them
+const SET_KEYS: &[&str] = &[
+    "autosave", "wrap", "truncate", "backup", "backup_ext", "backup_dir", "backup_keep",
+    "number", "hlsearch", "gitgutter", "numbers", "indent", "theme", "rustfmt_confirm",
+    "edition", "pager", "readonly", "trim_on_save", "autopair", "background", "prompt",
+    "prompt_format", "dict", "encoding",
+];
+
+const SNIP_KINDS: &str = "main, mod, test, struct Foo, enum Foo, trait Foo, derive [Foo]";
 
 // ===== Line reader (tedit-like) ======================================
 #[cfg(unix)]
@@ -27,28 +56,142 @@ fn enable_raw_mode(fd: i32) -> io::Result<libc::termios> {
             return Err(io::Error::last_os_error());
         }
         let mut raw = orig;
-        raw.c_lflag &= !(libc::ECHO | libc::ICANON);
-        raw.c_cc[libc::VMIN as usize] = 1;
-        raw.c_cc[libc::VTIME as usize] = 0;
+        // ISIG off so Ctrl-C reaches read_line as byte 3 instead of killing us
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
         if libc::tcsetattr(fd, libc::TCSAFLUSH, &raw) != 0 {
             return Err(io::Error::last_os_error());
         }
+        // bracketed paste: the terminal wraps pastes in ESC[200~ ... ESC[201~
+        print!("\x1b[?2004h");
+        let _ = io::stdout().flush();
         Ok(orig)
     }
 }
 
 #[cfg(unix)]
 fn disable_raw_mode(fd: i32, orig: &libc::termios) {
+    print!("\x1b[?2004l");
+    let _ = io::stdout().flush();
     unsafe {
         let _ = libc::tcsetattr(fd, libc::TCSAFLUSH, orig);
     }
 }
 
+// the termios to go back to while raw mode is on, so the panic hook can restore it too
+// (release builds abort on panic, so Drop alone wouldn't run)
+#[cfg(unix)]
+static RAW_SAVED: std::sync::Mutex<Option<(i32, libc::termios)>> = std::sync::Mutex::new(None);
+
+// raw mode for as long as this lives
+#[cfg(unix)]
+struct RawModeGuard {
+    fd: i32,
+    orig: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawModeGuard {
+    fn new(fd: i32) -> io::Result<Self> {
+        let orig = enable_raw_mode(fd)?;
+        if let Ok(mut saved) = RAW_SAVED.lock() {
+            *saved = Some((fd, orig));
+        }
+        Ok(Self { fd, orig })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        disable_raw_mode(self.fd, &self.orig);
+        if let Ok(mut saved) = RAW_SAVED.lock() {
+            *saved = None;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn install_panic_restore() {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Ok(mut saved) = RAW_SAVED.try_lock() {
+            if let Some((fd, orig)) = saved.take() {
+                disable_raw_mode(fd, &orig);
+            }
+        }
+        // drop the status bar's scroll region too, or the shell is stuck inside it
+        print!("\x1b[r");
+        prev(info);
+    }));
+}
+
+#[cfg(not(unix))]
+fn install_panic_restore() {}
+
+// set by the SIGWINCH handler, taken by the main loop before it draws the next prompt
+static RESIZED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    RESIZED.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+fn install_winch_handler() {
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = on_sigwinch as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SA_RESTART: a resize mustn't turn a blocking read into an error
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        libc::sigaction(libc::SIGWINCH, &sa, std::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+fn install_winch_handler() {}
+
+fn take_resized() -> bool {
+    RESIZED.swap(false, std::sync::atomic::Ordering::Relaxed)
+}
+
+// Tab state: the candidates offered for the last word, and which one is showing
+struct Completion {
+    cands: Vec<String>,
+    idx: Option<usize>,
+    // the line up to (not including) the word being completed
+    head: String,
+}
+
 struct LineReader {
     history: Vec<String>,
     hist_max: usize,
     commands: Vec<String>,
+    // user aliases, offered alongside the built-in commands
+    aliases: Vec<String>,
     input_color: String,
+    comp: Option<Completion>,
+    // candidates for the first argument of particular commands ("theme" -> theme names)
+    arg_words: HashMap<String, Vec<String>>,
+    // `set autopair`: typing ( [ { " also puts the closing one after the cursor
+    autopair: bool,
+    // Some while read_text runs: what Tab types instead of completing
+    text_tab: Option<String>,
+    // lines typed into append/insert, kept apart from the command history
+    text_history: Vec<String>,
+}
+
+// what autopair closes `open` with
+fn pair_close(open: char) -> Option<char> {
+    match open {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' => Some('"'),
+        _ => None,
+    }
 }
 
 impl LineReader {
@@ -57,7 +200,13 @@ impl LineReader {
             history: Vec::new(),
             hist_max: 800,
             commands: Vec::new(),
+            aliases: Vec::new(),
             input_color: String::new(),
+            comp: None,
+            arg_words: HashMap::new(),
+            autopair: false,
+            text_tab: None,
+            text_history: Vec::new(),
         }
     }
 
@@ -65,10 +214,34 @@ impl LineReader {
         self.commands = cmds.iter().map(|s| s.as_ref().to_string()).collect();
     }
 
+    fn set_aliases(&mut self, names: impl Iterator<Item = String>) {
+        let mut names: Vec<String> = names.filter(|n| !self.commands.contains(n)).collect();
+        names.sort();
+        self.aliases = names;
+    }
+
+    fn set_arg_words<S: AsRef<str>>(&mut self, cmds: &[&str], words: &[S]) {
+        let words: Vec<String> = words.iter().map(|s| s.as_ref().to_string()).collect();
+        for c in cmds {
+            self.arg_words.insert(c.to_string(), words.clone());
+        }
+    }
+
     fn set_input_color(&mut self, c: &str) {
         self.input_color = c.to_string();
     }
 
+    // read_line for a line of buffer text: no completion (Tab types `tab`), its own
+    // history, and Ctrl-C / end of input come back as errors so the caller can stop
+    fn read_text(&mut self, prompt: &str, tab: &str) -> io::Result<String> {
+        std::mem::swap(&mut self.history, &mut self.text_history);
+        self.text_tab = Some(tab.to_string());
+        let res = self.read_line(prompt);
+        self.text_tab = None;
+        std::mem::swap(&mut self.history, &mut self.text_history);
+        res
+    }
+
     fn remember(&mut self, s: &str) {
         if s.is_empty() {
             return;
@@ -85,13 +258,27 @@ impl LineReader {
         s.split_whitespace().collect()
     }
 
+    // start of the word left of `cursor`, skipping the whitespace in between
+    fn word_left(buf: &str, cursor: usize) -> usize {
+        let head = buf[..cursor].trim_end();
+        head.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0)
+    }
+
+    // end of the word right of `cursor`, skipping the whitespace in between
+    fn word_right(buf: &str, cursor: usize) -> usize {
+        let tail = &buf[cursor..];
+        let skip = tail.len() - tail.trim_start().len();
+        let word = tail[skip..].find(char::is_whitespace).unwrap_or(tail.len() - skip);
+        cursor + skip + word
+    }
+
     fn expand_home(token: &str) -> String {
         if token == "~" {
             return home_path().to_string_lossy().to_string();
         }
-        if token.starts_with("~/") {
+        if let Some(rest) = token.strip_prefix("~/") {
             let mut p = home_path();
-            p.push(&token[2..]);
+            p.push(rest);
             return p.to_string_lossy().to_string();
         }
         token.to_string()
@@ -161,19 +348,25 @@ impl LineReader {
         let at_start = toks.is_empty();
         let fresh = !buf.is_empty() && buf.ends_with(char::is_whitespace);
         if at_start {
-            return self.commands.clone();
+            return self.commands.iter().chain(&self.aliases).cloned().collect();
         }
         if toks.len() == 1 && !fresh {
             let pref = toks[0];
             return self
             .commands
             .iter()
+            .chain(&self.aliases)
             .filter(|c| c.starts_with(pref))
             .cloned()
             .collect();
         }
         // after first word
         let first = toks[0];
+        let second = (toks.len() == 1 && fresh) || (toks.len() == 2 && !fresh);
+        if let Some(words) = self.arg_words.get(first).filter(|_| second) {
+            let pref = if fresh { "" } else { toks[1] };
+            return words.iter().filter(|w| w.starts_with(pref)).cloned().collect();
+        }
         if first == "cd" {
             let last = if fresh { "" } else { toks[toks.len() - 1] };
             return Self::complete_dirs_only(last);
@@ -182,6 +375,66 @@ impl LineReader {
         Self::complete_fs(last)
     }
 
+    // first Tab: complete a lone candidate, or fill in the common prefix and list them all;
+    // further Tabs (Shift-Tab backwards) cycle the candidates in place
+    fn tab(&mut self, prompt: &str, buf: &mut String, cursor: &mut usize, forward: bool) {
+        if let Some(st) = self.comp.as_mut() {
+            let n = st.cands.len();
+            let i = match (st.idx, forward) {
+                (None, true) => 0,
+                (None, false) => n - 1,
+                (Some(i), true) => (i + 1) % n,
+                (Some(i), false) => (i + n - 1) % n,
+            };
+            st.idx = Some(i);
+            *buf = format!("{}{}", st.head, st.cands[i]);
+            *cursor = buf.len();
+            self.redraw(prompt, buf, *cursor);
+            return;
+        }
+        let opts = self.complete(buf);
+        if opts.is_empty() {
+            return;
+        }
+        let head = match buf.rfind(' ') {
+            Some(idx) if !buf.trim().is_empty() => buf[..idx + 1].to_string(),
+            _ => String::new(),
+        };
+        if opts.len() == 1 {
+            *buf = format!("{}{}", head, opts[0]);
+            *cursor = buf.len();
+            self.redraw(prompt, buf, *cursor);
+            return;
+        }
+        let mut lcp = opts[0].as_str();
+        for o in &opts[1..] {
+            let n = lcp.bytes().zip(o.bytes()).take_while(|(a, b)| a == b).count();
+            let mut n = n.min(lcp.len());
+            while !lcp.is_char_boundary(n) {
+                n -= 1;
+            }
+            lcp = &lcp[..n];
+        }
+        if lcp.len() > buf.len() - head.len() {
+            *buf = format!("{}{}", head, lcp);
+            *cursor = buf.len();
+        }
+        println!();
+        let mut c = 0;
+        for o in &opts {
+            print!("{}  ", o);
+            c += 1;
+            if c % 6 == 0 {
+                println!();
+            }
+        }
+        if c % 6 != 0 {
+            println!();
+        }
+        self.redraw(prompt, buf, *cursor);
+        self.comp = Some(Completion { cands: opts, idx: None, head });
+    }
+
     fn redraw(&self, prompt: &str, buf: &str, cursor: usize) {
         print!("\r\x1b[2K{}{}{}\x1b[0m", prompt, self.input_color, buf);
         let tail = buf.len().saturating_sub(cursor);
@@ -191,6 +444,37 @@ impl LineReader {
         let _ = io::stdout().flush();
     }
 
+    // everything up to ESC [ 201 ~ as typed text: newlines become spaces so a pasted
+    // block can't run as several commands, other control bytes are dropped
+    #[cfg(unix)]
+    fn read_paste(stdin: &io::Stdin) -> io::Result<String> {
+        const END: &[u8] = b"\x1b[201~";
+        let mut raw: Vec<u8> = Vec::new();
+        let mut byte = [0u8; 1];
+        while !raw.ends_with(END) {
+            if stdin.lock().read(&mut byte)? == 0 {
+                break;
+            }
+            raw.push(byte[0]);
+        }
+        if raw.ends_with(END) {
+            raw.truncate(raw.len() - END.len());
+        }
+        while raw.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+            raw.pop();
+        }
+        let text = String::from_utf8_lossy(&raw);
+        Ok(text
+            .chars()
+            .filter_map(|c| match c {
+                '\r' | '\n' => Some(' '),
+                '\t' => Some(c),
+                c if c.is_control() => None,
+                c => Some(c),
+            })
+            .collect())
+    }
+
     #[cfg(unix)]
     fn read_line(&mut self, prompt: &str) -> io::Result<String> {
         use std::os::fd::AsRawFd;
@@ -200,7 +484,8 @@ impl LineReader {
 
         let stdin = io::stdin();
         let fd = stdin.as_raw_fd();
-        let orig = enable_raw_mode(fd)?;
+        let _raw = RawModeGuard::new(fd)?;
+        self.comp = None;
 
         let mut buf = String::new();
         let mut cursor: usize = 0;
@@ -209,114 +494,185 @@ impl LineReader {
         loop {
             let mut byte = [0u8; 1];
             if stdin.lock().read(&mut byte)? == 0 {
-                disable_raw_mode(fd, &orig);
+                if self.text_tab.is_some() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
                 return Ok(String::new());
             }
             let b = byte[0];
+            if b != b'\t' && b != 27 {
+                self.comp = None;
+            }
             match b {
                 b'\r' | b'\n' => {
                     println!();
-                    disable_raw_mode(fd, &orig);
                     self.remember(&buf);
                     return Ok(buf);
                 }
                 127 | 8 => {
                     if cursor > 0 {
+                        // backspace inside an empty pair takes the closer with it
+                        let open = buf.as_bytes()[cursor - 1] as char;
+                        let next = buf.as_bytes().get(cursor).map(|&c| c as char);
+                        if self.autopair && next.is_some() && pair_close(open) == next {
+                            buf.remove(cursor);
+                        }
                         buf.remove(cursor - 1);
                         cursor -= 1;
                         self.redraw(prompt, &buf, cursor);
                     }
                 }
                 b'\t' => {
-                    let opts = self.complete(&buf);
-                    if opts.is_empty() {
-                        // nothing
-                    } else if opts.len() == 1 {
-                        // single completion
-                        let mut toks = buf.split_whitespace().collect::<Vec<_>>();
-                        if toks.is_empty() {
-                            buf = opts[0].clone();
-                        } else {
-                            // replace last token
-                            let lastsp = buf.rfind(' ');
-                            if let Some(idx) = lastsp {
-                                buf = format!("{}{}", &buf[..idx + 1], opts[0]);
-                            } else {
-                                buf = opts[0].clone();
-                            }
-                        }
-                        cursor = buf.len();
+                    if let Some(t) = &self.text_tab {
+                        buf.insert_str(cursor, t);
+                        cursor += t.len();
                         self.redraw(prompt, &buf, cursor);
                     } else {
-                        // show options
-                        println!();
-                        let mut c = 0;
-                        for o in &opts {
-                            print!("{}  ", o);
-                            c += 1;
-                            if c % 6 == 0 {
-                                println!();
-                            }
-                        }
-                        if c % 6 != 0 {
-                            println!();
-                        }
-                        self.redraw(prompt, &buf, cursor);
+                        self.tab(prompt, &mut buf, &mut cursor, true);
                     }
                 }
                 27 => {
-                    // escape
+                    // escape: ESC [ <letter>, ESC [ <digits> ~ (bracketed paste markers),
+                    // ESC [ 1 ; <mod> <letter> (alt/ctrl-arrows), or ESC b / ESC f (alt-b/f)
                     let mut seq = [0u8; 2];
-                    if stdin.lock().read(&mut seq[..1]).is_ok() && seq[0] == b'[' {
-                        if stdin.lock().read(&mut seq[1..2]).is_ok() {
-                            match seq[1] {
-                                b'A' => {
-                                    // up
-                                    if hist_idx > 0 {
-                                        hist_idx -= 1;
-                                        buf = self.history[hist_idx as usize].clone();
-                                        cursor = buf.len();
-                                        self.redraw(prompt, &buf, cursor);
-                                    }
-                                }
-                                b'B' => {
-                                    // down
-                                    if hist_idx < self.history.len() as isize - 1 {
-                                        hist_idx += 1;
-                                        buf = self.history[hist_idx as usize].clone();
-                                        cursor = buf.len();
-                                        self.redraw(prompt, &buf, cursor);
-                                    } else {
-                                        hist_idx = self.history.len() as isize;
-                                        buf.clear();
-                                        cursor = 0;
-                                        self.redraw(prompt, &buf, cursor);
-                                    }
-                                }
-                                b'C' => {
-                                    // right
-                                    if cursor < buf.len() {
-                                        cursor += 1;
-                                        self.redraw(prompt, &buf, cursor);
-                                    }
+                    if stdin.lock().read(&mut seq[..1]).is_ok() && matches!(seq[0], b'b' | b'f') {
+                        self.comp = None;
+                        cursor = if seq[0] == b'b' {
+                            Self::word_left(&buf, cursor)
+                        } else {
+                            Self::word_right(&buf, cursor)
+                        };
+                        self.redraw(prompt, &buf, cursor);
+                    } else if seq[0] == b'[' && stdin.lock().read(&mut seq[1..2]).is_ok() {
+                        let mut num = String::new();
+                        while seq[1].is_ascii_digit() {
+                            num.push(seq[1] as char);
+                            if stdin.lock().read(&mut seq[1..2])? == 0 {
+                                break;
+                            }
+                        }
+                        // anything but shift-tab ends a Tab cycle, or the next Tab would
+                        // put the old candidate back over whatever this key did
+                        if seq[1] != b'Z' {
+                            self.comp = None;
+                        }
+                        if seq[1] == b';' {
+                            // modified key: alt (3) or ctrl (5) + left/right jumps a word
+                            let mut m = [0u8; 1];
+                            let mut modifier = String::new();
+                            while stdin.lock().read(&mut m)? == 1 && m[0].is_ascii_digit() {
+                                modifier.push(m[0] as char);
+                            }
+                            if matches!(modifier.as_str(), "3" | "5") {
+                                match m[0] {
+                                    b'D' => cursor = Self::word_left(&buf, cursor),
+                                    b'C' => cursor = Self::word_right(&buf, cursor),
+                                    _ => continue,
                                 }
-                                b'D' => {
-                                    // left
-                                    if cursor > 0 {
-                                        cursor -= 1;
-                                        self.redraw(prompt, &buf, cursor);
-                                    }
+                                self.redraw(prompt, &buf, cursor);
+                            }
+                            continue;
+                        }
+                        if num == "200" && seq[1] == b'~' {
+                            let pasted = Self::read_paste(&stdin)?;
+                            buf.insert_str(cursor, &pasted);
+                            cursor += pasted.len();
+                            self.redraw(prompt, &buf, cursor);
+                            continue;
+                        }
+                        if !num.is_empty() {
+                            continue;
+                        }
+                        match seq[1] {
+                            b'Z' if self.text_tab.is_none() => {
+                                // shift-tab
+                                self.tab(prompt, &mut buf, &mut cursor, false);
+                            }
+                            b'A' if hist_idx > 0 => {
+                                // up
+                                hist_idx -= 1;
+                                buf = self.history[hist_idx as usize].clone();
+                                cursor = buf.len();
+                                self.redraw(prompt, &buf, cursor);
+                            }
+                            b'B' => {
+                                // down
+                                if hist_idx < self.history.len() as isize - 1 {
+                                    hist_idx += 1;
+                                    buf = self.history[hist_idx as usize].clone();
+                                    cursor = buf.len();
+                                    self.redraw(prompt, &buf, cursor);
+                                } else {
+                                    hist_idx = self.history.len() as isize;
+                                    buf.clear();
+                                    cursor = 0;
+                                    self.redraw(prompt, &buf, cursor);
                                 }
-                                _ => {}
                             }
+                            b'C' if cursor < buf.len() => {
+                                // right
+                                cursor += 1;
+                                self.redraw(prompt, &buf, cursor);
+                            }
+                            b'D' if cursor > 0 => {
+                                // left
+                                cursor -= 1;
+                                self.redraw(prompt, &buf, cursor);
+                            }
+                            _ => {}
                         }
                     }
                 }
+                3 => {
+                    // ctrl-c: drop the line and re-prompt, like a shell
+                    println!("^C");
+                    if self.text_tab.is_some() {
+                        return Err(io::ErrorKind::Interrupted.into());
+                    }
+                    return Ok(String::new());
+                }
+                1 => {
+                    // ctrl-a: home
+                    cursor = 0;
+                    self.redraw(prompt, &buf, cursor);
+                }
+                5 => {
+                    // ctrl-e: end
+                    cursor = buf.len();
+                    self.redraw(prompt, &buf, cursor);
+                }
+                11 => {
+                    // ctrl-k: kill to end of line
+                    buf.truncate(cursor);
+                    self.redraw(prompt, &buf, cursor);
+                }
+                21 => {
+                    // ctrl-u: kill to start of line
+                    buf.replace_range(..cursor, "");
+                    cursor = 0;
+                    self.redraw(prompt, &buf, cursor);
+                }
+                23 => {
+                    // ctrl-w: delete the word before the cursor (and the spaces after it)
+                    let start = Self::word_left(&buf, cursor);
+                    buf.replace_range(start..cursor, "");
+                    cursor = start;
+                    self.redraw(prompt, &buf, cursor);
+                }
                 _ => {
                     // printable-ish
                     let ch = b as char;
-                    buf.insert(cursor, ch);
-                    cursor += 1;
+                    let next = buf.as_bytes().get(cursor).map(|&c| c as char);
+                    if self.autopair && matches!(ch, ')' | ']' | '}' | '"') && next == Some(ch) {
+                        // typing the closer autopair already put there just steps over it
+                        cursor += 1;
+                    } else {
+                        buf.insert(cursor, ch);
+                        cursor += 1;
+                        if let Some(close) = pair_close(ch).filter(|_| self.autopair) {
+                            buf.insert(cursor, close);
+                        }
+                    }
                     self.redraw(prompt, &buf, cursor);
                 }
             }
@@ -343,8 +699,28 @@ struct Buffer {
     lines: Vec<String>,
     dirty: bool,
     number: bool,
+    // with `number`: show distance from the current line instead (`set numbers relative`)
+    relnum: bool,
     backup: bool,
+    // `set hlsearch`: print shows the last search's matches in the accent color
     highlight: bool,
+    // how the file on disk ended its lines, so saving doesn't rewrite them
+    crlf: bool,
+    final_newline: bool,
+    // set via `set readonly`, `-r`, or when the file on disk isn't writable
+    readonly: bool,
+    // `mark a` .. `mark z`, as line numbers in this buffer
+    marks: HashMap<char, usize>,
+    // the current line (`.`), and the top row of the last `view`/`next`/`prev` window
+    cursor_line: usize,
+    view_top: usize,
+    // `set gitgutter`: filled on first draw, dropped on (re)load and when gitgutter is toggled
+    git: RefCell<Option<GitCache>>,
+    // "utf-8", or "windows-1252" when the file wasn't valid UTF-8; saves write it back that way
+    encoding: &'static str,
+    // each buffer keeps its own history so bnext/bprev don't mix them up
+    undo: Stack,
+    redo: Stack,
 }
 
 impl Buffer {
@@ -354,8 +730,19 @@ impl Buffer {
             lines: Vec::new(),
             dirty: false,
             number: true,
+            relnum: false,
             backup: true,
             highlight: false,
+            crlf: false,
+            final_newline: true,
+            encoding: "utf-8",
+            readonly: false,
+            git: RefCell::new(None),
+            marks: HashMap::new(),
+            cursor_line: 0,
+            view_top: 0,
+            undo: Stack::new(),
+            redo: Stack::new(),
         }
     }
 
@@ -369,34 +756,81 @@ impl Buffer {
     fn char_count(&self) -> usize {
         self.lines.iter().map(|l| l.len() + 1).sum()
     }
+
+    // strip trailing spaces/tabs as one undo step; returns how many lines changed
+    fn trim_trailing(&mut self) -> usize {
+        let dirty_lines = self.lines.iter().filter(|l| l.ends_with([' ', '\t'])).count();
+        if dirty_lines == 0 {
+            return 0;
+        }
+        self.undo.push(&self.lines, 1, self.lines.len());
+        self.redo.clear();
+        for l in self.lines.iter_mut() {
+            let n = l.trim_end_matches([' ', '\t']).len();
+            l.truncate(n);
+        }
+        self.dirty = true;
+        dirty_lines
+    }
 }
 
 #[derive(Clone)]
 struct Snap {
     lines: Vec<String>,
+    // 1-based lines the edit touched, so undo/redo can show where it happened
+    lo: usize,
+    hi: usize,
 }
 
+#[derive(Clone)]
 struct Stack {
     st: Vec<Snap>,
+    last_kind: String,
+    last_at: Option<Instant>,
 }
 
 impl Stack {
     fn new() -> Self {
-        Self { st: Vec::new() }
+        Self {
+            st: Vec::new(),
+            last_kind: String::new(),
+            last_at: None,
+        }
     }
-    fn push(&mut self, buf: &Buffer) {
+    fn push(&mut self, lines: &[String], lo: usize, hi: usize) {
         if self.st.len() == UNDO_MAX {
             self.st.remove(0);
         }
         self.st.push(Snap {
-            lines: buf.lines.clone(),
+            lines: lines.to_vec(),
+            lo,
+            hi,
         });
+        self.last_at = None;
+    }
+    // like push, but skipped when the previous push was the same kind a moment ago
+    fn push_coalesced(&mut self, lines: &[String], lo: usize, hi: usize, kind: &str) {
+        let recent = self.last_at.map(|t| t.elapsed() < UNDO_COALESCE).unwrap_or(false);
+        if recent && self.last_kind == kind && !self.st.is_empty() {
+            if let Some(top) = self.st.last_mut() {
+                top.lo = top.lo.min(lo);
+                top.hi = top.hi.max(hi);
+            }
+            self.last_at = Some(Instant::now());
+            return;
+        }
+        self.push(lines, lo, hi);
+        self.last_kind = kind.to_string();
+        self.last_at = Some(Instant::now());
     }
     fn pop(&mut self) -> Option<Snap> {
+        // after an undo/redo the next edit must get its own snapshot
+        self.last_at = None;
         self.st.pop()
     }
     fn clear(&mut self) {
         self.st.clear();
+        self.last_at = None;
     }
 }
 
@@ -407,6 +841,22 @@ enum Theme {
     Neon,
     Matrix,
     Paper,
+    Solarized,
+    Gruvbox,
+    Nord,
+}
+
+impl Theme {
+    const ALL: [Theme; 8] = [
+        Theme::Default,
+        Theme::Dark,
+        Theme::Neon,
+        Theme::Matrix,
+        Theme::Paper,
+        Theme::Solarized,
+        Theme::Gruvbox,
+        Theme::Nord,
+    ];
 }
 
 struct Palette {
@@ -424,11 +874,94 @@ struct Palette {
     help_text: &'static str,
 }
 
+fn theme_from_name(name: &str) -> Option<Theme> {
+    match lower(name).as_str() {
+        "default" => Some(Theme::Default),
+        "dark" => Some(Theme::Dark),
+        "neon" => Some(Theme::Neon),
+        "matrix" => Some(Theme::Matrix),
+        "paper" => Some(Theme::Paper),
+        "solarized" => Some(Theme::Solarized),
+        "gruvbox" => Some(Theme::Gruvbox),
+        "nord" => Some(Theme::Nord),
+        _ => None,
+    }
+}
+
+// 0 = auto (tty and no NO_COLOR), 1 = never, 2 = always; set once from the command line
+static COLOR_MODE: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+
+fn set_color_mode(mode: u8) {
+    COLOR_MODE.store(mode, std::sync::atomic::Ordering::Relaxed);
+}
+
 fn use_color() -> bool {
-    atty::is(Stream::Stdout)
+    match COLOR_MODE.load(std::sync::atomic::Ordering::Relaxed) {
+        1 => false,
+        2 => true,
+        _ => {
+            let no_color = std::env::var_os("NO_COLOR").map(|v| !v.is_empty()).unwrap_or(false);
+            !no_color && atty::is(Stream::Stdout)
+        }
+    }
+}
+
+// COLORTERM is how terminals advertise 24-bit color; nothing to probe beyond that
+fn truecolor() -> bool {
+    matches!(std::env::var("COLORTERM").as_deref(), Ok("truecolor") | Ok("24bit"))
+}
+
+// 24-bit takes on the themes where the prompt gradient benefits; None = use the 16-color one
+fn truecolor_palette(t: Theme) -> Option<Palette> {
+    const DIM: &str = "\x1b[2m";
+    match t {
+        Theme::Default => Some(Palette {
+            accent: "\x1b[38;2;40;190;235m",
+            ok: "\x1b[38;2;90;200;120m",
+            warn: "\x1b[38;2;230;200;80m",
+            err: "\x1b[38;2;230;80;80m",
+            dim: DIM,
+            prompt: "\x1b[38;2;40;190;235m",
+            input: "\x1b[38;2;240;240;240m", // white
+            gutter: "\x1b[38;2;110;110;110m",
+            title: "\x1b[1;38;2;0;220;255m",
+            help_cmd: "\x1b[38;2;110;170;240m",
+            help_arg: DIM,
+            help_text: DIM,
+        }),
+        Theme::Neon => Some(Palette {
+            accent: "\x1b[38;2;230;90;255m",
+            ok: "\x1b[38;2;80;255;160m",
+            warn: "\x1b[38;2;255;230;90m",
+            err: "\x1b[38;2;255;80;110m",
+            dim: "\x1b[38;2;120;120;140m",
+            prompt: "\x1b[38;2;230;90;255m",
+            input: "\x1b[38;2;90;200;255m", // light neon blue
+            gutter: "\x1b[38;2;120;120;140m",
+            title: "\x1b[1;38;2;255;60;200m",
+            help_cmd: "\x1b[38;2;180;110;255m",
+            help_arg: "\x1b[38;2;120;120;140m",
+            help_text: "\x1b[38;2;120;120;140m",
+        }),
+        _ => None,
+    }
+}
+
+// `set background light` swaps the near-white input colors for ones a light terminal can show
+fn palette_for(t: Theme, light_bg: bool) -> Palette {
+    let mut p = theme_palette(t);
+    if light_bg && use_color() {
+        p.input = match t {
+            Theme::Neon => "\x1b[36m",
+            Theme::Matrix => "\x1b[32m",
+            Theme::Paper => p.input,
+            _ => "\x1b[30m",
+        };
+    }
+    p
 }
 
-fn palette_for(t: Theme) -> Palette {
+fn theme_palette(t: Theme) -> Palette {
     if !use_color() {
         return Palette {
             accent: "",
@@ -445,6 +978,11 @@ fn palette_for(t: Theme) -> Palette {
             help_text: "",
         };
     }
+    if truecolor() {
+        if let Some(p) = truecolor_palette(t) {
+            return p;
+        }
+    }
 
     const DIM: &str = "\x1b[2m";
     const GREEN: &str = "\x1b[32m";
@@ -461,7 +999,35 @@ fn palette_for(t: Theme) -> Palette {
     const BOLD_CYAN: &str = "\x1b[1;36m";
     const BOLD_MAG: &str = "\x1b[1;95m";
     const BOLD_GREEN: &str = "\x1b[1;32m";
-    const BOLD_BLACK: &str = "\x1b[1;90m";
+    const BLACK: &str = "\x1b[30m";
+    const BLUE: &str = "\x1b[34m";
+    const BOLD_BLUE: &str = "\x1b[1;34m";
+
+    // 256-color picks for the schemes people match their terminals to
+    const SOL_BLUE: &str = "\x1b[38;5;33m";
+    const SOL_CYAN: &str = "\x1b[38;5;37m";
+    const SOL_GREEN: &str = "\x1b[38;5;64m";
+    const SOL_YEL: &str = "\x1b[38;5;136m";
+    const SOL_RED: &str = "\x1b[38;5;160m";
+    const SOL_BASE01: &str = "\x1b[38;5;240m";
+    const SOL_BASE1: &str = "\x1b[38;5;245m";
+    const SOL_TITLE: &str = "\x1b[1;38;5;33m";
+    const GRV_ORANGE: &str = "\x1b[38;5;208m";
+    const GRV_YEL: &str = "\x1b[38;5;214m";
+    const GRV_GREEN: &str = "\x1b[38;5;142m";
+    const GRV_RED: &str = "\x1b[38;5;167m";
+    const GRV_AQUA: &str = "\x1b[38;5;108m";
+    const GRV_GRAY: &str = "\x1b[38;5;245m";
+    const GRV_FG: &str = "\x1b[38;5;223m";
+    const GRV_TITLE: &str = "\x1b[1;38;5;214m";
+    const NORD_FROST: &str = "\x1b[38;5;110m";
+    const NORD_TEAL: &str = "\x1b[38;5;109m";
+    const NORD_GREEN: &str = "\x1b[38;5;144m";
+    const NORD_YEL: &str = "\x1b[38;5;222m";
+    const NORD_RED: &str = "\x1b[38;5;131m";
+    const NORD_GRAY: &str = "\x1b[38;5;60m";
+    const NORD_SNOW: &str = "\x1b[38;5;188m";
+    const NORD_TITLE: &str = "\x1b[1;38;5;110m";
 
     match t {
         Theme::Dark => Palette {
@@ -507,18 +1073,61 @@ fn palette_for(t: Theme) -> Palette {
             help_text: BBLACK,
         },
         Theme::Paper => Palette {
-            accent: BBLACK,
+            // plain dark ink for light backgrounds; no bright/gray codes that wash out
+            accent: BLUE,
             ok: GREEN,
             warn: YEL,
             err: RED,
-            dim: BBLACK,
-            prompt: BBLACK,
-            input: BBLACK, // gray
-            gutter: BBLACK,
-            title: BOLD_BLACK,
-            help_cmd: BBLACK,
-            help_arg: BBLACK,
-            help_text: BBLACK,
+            dim: DIM,
+            prompt: BLUE,
+            input: BLACK, // black
+            gutter: DIM,
+            title: BOLD_BLUE,
+            help_cmd: BLUE,
+            help_arg: DIM,
+            help_text: BLACK,
+        },
+        Theme::Solarized => Palette {
+            accent: SOL_BLUE,
+            ok: SOL_GREEN,
+            warn: SOL_YEL,
+            err: SOL_RED,
+            dim: SOL_BASE01,
+            prompt: SOL_BLUE,
+            input: SOL_BASE1, // base1
+            gutter: SOL_BASE01,
+            title: SOL_TITLE,
+            help_cmd: SOL_CYAN,
+            help_arg: SOL_BASE01,
+            help_text: SOL_BASE01,
+        },
+        Theme::Gruvbox => Palette {
+            accent: GRV_ORANGE,
+            ok: GRV_GREEN,
+            warn: GRV_YEL,
+            err: GRV_RED,
+            dim: GRV_GRAY,
+            prompt: GRV_ORANGE,
+            input: GRV_FG, // cream
+            gutter: GRV_GRAY,
+            title: GRV_TITLE,
+            help_cmd: GRV_AQUA,
+            help_arg: GRV_GRAY,
+            help_text: GRV_GRAY,
+        },
+        Theme::Nord => Palette {
+            accent: NORD_FROST,
+            ok: NORD_GREEN,
+            warn: NORD_YEL,
+            err: NORD_RED,
+            dim: NORD_GRAY,
+            prompt: NORD_FROST,
+            input: NORD_SNOW, // snow storm
+            gutter: NORD_GRAY,
+            title: NORD_TITLE,
+            help_cmd: NORD_TEAL,
+            help_arg: NORD_GRAY,
+            help_text: NORD_GRAY,
         },
         Theme::Default => Palette {
             accent: CYAN,
@@ -551,6 +1160,22 @@ fn lower(s: &str) -> String {
     s.chars().map(|c| c.to_ascii_lowercase()).collect()
 }
 
+fn on_off(b: bool) -> &'static str {
+    if b {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn parse_on_off(s: &str) -> Option<bool> {
+    match lower(s).as_str() {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
 fn digits_for(mut n: usize) -> usize {
     let mut w = 1;
     while n >= 10 {
@@ -560,421 +1185,3714 @@ fn digits_for(mut n: usize) -> usize {
     w
 }
 
-fn load_file(path: &Path, buf: &mut Buffer) -> io::Result<()> {
-    buf.lines.clear();
-    let f = File::open(path)?;
-    let reader = io::BufReader::new(f);
-    for line in reader.lines() {
-        buf.lines.push(line?);
+// NUL bytes, or lots of control bytes, in the first 8 KiB
+fn is_binary(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(8192)];
+    if head.contains(&0) {
+        return true;
     }
-    buf.dirty = false;
-    Ok(())
+    let odd = head
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    odd * 10 > head.len()
 }
 
-fn atomic_save(path: &Path, buf: &Buffer, backup: bool) -> io::Result<()> {
-    if backup && path.exists() {
-        let mut backup_path = path.to_path_buf();
-        backup_path.set_extension("~");
-        let _ = fs::copy(path, &backup_path);
+// control characters as ^X / \xNN so printing a line can't mess up the terminal
+fn escape_controls(s: &str) -> std::borrow::Cow<'_, str> {
+    if !s.chars().any(|c| c.is_control() && c != '\t') {
+        return std::borrow::Cow::Borrowed(s);
     }
-    let mut tmp = path
-    .parent()
-    .unwrap_or_else(|| Path::new("."))
-    .to_path_buf();
-    tmp.push(format!(".{}.tmp.{}", APP_NAME, std::process::id()));
-    {
-        #[cfg(unix)]
-        let mut f = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .mode(0o644)
-        .open(&tmp)?;
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\t' => out.push(c),
+            '\x7f' => out.push_str("^?"),
+            c if (c as u32) < 0x20 => {
+                out.push('^');
+                out.push((c as u8 + b'@') as char);
+            }
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    std::borrow::Cow::Owned(out)
+}
+
+// classic `offset  hex  |ascii|` rows, `per_row` bytes each
+fn hex_dump(bytes: &[u8], start: usize, per_row: usize) -> String {
+    let mut out = String::new();
+    for (r, row) in bytes.chunks(per_row).enumerate() {
+        out.push_str(&format!("{:08x}  ", start + r * per_row));
+        for i in 0..per_row {
+            match row.get(i) {
+                Some(b) => out.push_str(&format!("{:02x} ", b)),
+                None => out.push_str("   "),
+            }
+            if i == 7 && per_row > 8 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        for &b in row {
+            out.push(if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(unix)]
+fn file_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(c) => unsafe { libc::access(c.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn file_writable(path: &Path) -> bool {
+    fs::metadata(path).map(|m| !m.permissions().readonly()).unwrap_or(true)
+}
+
+// windows-1252's 0x80..=0x9f; the five holes keep their latin-1 (C1 control) meaning
+const CP1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡',
+    'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—',
+    '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+fn decode_cp1252(bytes: &[u8]) -> String {
+    bytes
+    .iter()
+    .map(|&b| match b {
+        0x80..=0x9f => CP1252_HIGH[(b - 0x80) as usize],
+        _ => b as char,
+    })
+    .collect()
+}
 
-        #[cfg(not(unix))]
-        let mut f = OpenOptions::new().write(true).create(true).open(&tmp)?;
+// None if some char has no windows-1252 byte
+fn encode_cp1252(s: &str) -> Option<Vec<u8>> {
+    s.chars()
+    .map(|c| match c as u32 {
+        0..=0x7f | 0xa0..=0xff => Some(c as u8),
+        _ => CP1252_HIGH.iter().position(|&h| h == c).map(|i| 0x80 + i as u8),
+    })
+    .collect()
+}
 
-        for l in &buf.lines {
-            f.write_all(l.as_bytes())?;
-            f.write_all(b"\n")?;
+fn load_file(path: &Path, buf: &mut Buffer) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    if is_binary(&bytes) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "looks like a binary file"));
+    }
+    // not UTF-8: assume the usual legacy suspect and remember it, so saving writes it back
+    let (text, encoding) = match String::from_utf8(bytes) {
+        Ok(t) => (t, "utf-8"),
+        Err(e) => (decode_cp1252(e.as_bytes()), "windows-1252"),
+    };
+    // build everything first; `buf` is only touched once nothing can fail any more
+    let body = text.strip_suffix('\n').unwrap_or(&text);
+    let mut lines = Vec::new();
+    if !text.is_empty() {
+        for line in body.split('\n') {
+            lines.push(line.strip_suffix('\r').unwrap_or(line).to_string());
         }
-        f.flush()?;
-        f.sync_all()?;
     }
-    fs::rename(&tmp, path)?;
+    // the first line ending decides the style for the whole file
+    buf.crlf = text.find('\n').map(|i| text[..i].ends_with('\r')).unwrap_or(false);
+    buf.final_newline = text.is_empty() || text.ends_with('\n');
+    buf.encoding = encoding;
+    buf.lines = lines;
+    buf.dirty = false;
     Ok(())
 }
 
-fn detect_lang_from_path(path: Option<&PathBuf>) -> &'static str {
-    if let Some(p) = path {
-        if let Some(ext) = p.extension().and_then(|e| e.to_str()) {
-            let ext = ext.to_ascii_lowercase();
-            return match ext.as_str() {
-                "rs" => "rust",
-                "c" | "cc" | "cpp" | "cxx" | "h" | "hpp" => "cpp",
-                "py" => "python",
-                "sh" | "bash" | "zsh" => "shell",
-                "js" | "ts" => "js",
-                "html" | "htm" => "html",
-                "css" => "css",
-                "json" => "json",
-                _ => "plain",
-            };
+// where `set backup_*` sends the copy of the old file before each save
+struct BackupOpts {
+    ext: String,
+    dir: Option<PathBuf>,
+    // numbered copies to keep (name.1~ newest); 0 or 1 = one copy, overwritten each save
+    keep: usize,
+}
+
+// foo.rs -> foo.rs~ next to it, or <dir>/%home%me%foo.rs~ so same-named files don't collide
+fn backup_path(path: &Path, opts: &BackupOpts, n: usize) -> PathBuf {
+    let mut name = match &opts.dir {
+        Some(_) => {
+            let abs = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+            abs.to_string_lossy().replace(['/', '\\'], "%")
         }
+        None => path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+    };
+    if n > 0 {
+        name.push_str(&format!(".{}", n));
+    }
+    name.push_str(&opts.ext);
+    match &opts.dir {
+        Some(d) => d.join(name),
+        None => path.with_file_name(name),
     }
-    "plain"
 }
 
-fn term_width() -> usize {
-    if let Some((w, _)) = term_size::dimensions() {
-        w
-    } else {
-        80
+fn write_backup(path: &Path, opts: &BackupOpts) -> io::Result<()> {
+    if let Some(d) = &opts.dir {
+        fs::create_dir_all(d)?;
+    }
+    if opts.keep <= 1 {
+        return fs::copy(path, backup_path(path, opts, 0)).map(|_| ());
     }
+    // rotate: drop the oldest, shift the rest up one, newest becomes .1
+    let _ = fs::remove_file(backup_path(path, opts, opts.keep));
+    for n in (1..opts.keep).rev() {
+        let _ = fs::rename(backup_path(path, opts, n), backup_path(path, opts, n + 1));
+    }
+    fs::copy(path, backup_path(path, opts, 1)).map(|_| ())
 }
 
-fn parse_range(s: &str, nlines: usize) -> Option<(usize, usize)> {
-    let s = s.trim();
-    if s.is_empty() {
-        return Some((1, nlines));
-    }
-    if let Some(idx) = s.find('-') {
-        let left = &s[..idx];
-        let right = &s[idx + 1..];
-        let lo = if left.is_empty() {
-            1
-        } else {
-            left.parse::<usize>().ok()?
-        };
-        let hi = if right.is_empty() {
-            nlines
+const NOT_ATOMIC: &str = "note: not atomic; couldn't rename beside the file, copied over it";
+
+// Ok(false): the file was written, but by copying in place rather than an atomic rename
+fn atomic_save(path: &Path, buf: &Buffer, backup: Option<&BackupOpts>) -> io::Result<bool> {
+    // encode up front so a char windows-1252 can't hold fails before anything is touched
+    let eol: &[u8] = if buf.crlf { b"\r\n" } else { b"\n" };
+    let mut data = Vec::new();
+    for (i, l) in buf.lines.iter().enumerate() {
+        if buf.encoding == "windows-1252" {
+            let bytes = encode_cp1252(l).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "line {} has chars windows-1252 can't hold (set encoding utf-8)",
+                        i + 1
+                    ),
+                )
+            })?;
+            data.extend_from_slice(&bytes);
         } else {
-            right.parse::<usize>().ok()?
-        };
-        if lo == 0 || hi == 0 || lo > hi {
-            return None;
+            data.extend_from_slice(l.as_bytes());
         }
-        Some((lo, hi.min(nlines)))
-    } else {
-        let n = s.parse::<usize>().ok()?;
-        if n == 0 {
-            return None;
+        if i + 1 < buf.lines.len() || buf.final_newline {
+            data.extend_from_slice(eol);
+        }
+    }
+    if let Some(opts) = backup {
+        if path.exists() {
+            // best effort, like always: a backup that can't be made shouldn't block the save
+            let _ = write_backup(path, opts);
+        }
+    }
+    let mut tmp = path
+    .parent()
+    .unwrap_or_else(|| Path::new("."))
+    .to_path_buf();
+    let tmp_name = format!(".{}.tmp.{}", APP_NAME, std::process::id());
+    tmp.push(&tmp_name);
+    let attempt = write_temp(&tmp, path, &data).and_then(|_| fs::rename(&tmp, path));
+    match attempt {
+        Ok(()) => {
+            sync_dir(tmp.parent().unwrap_or_else(|| Path::new(".")));
+            Ok(true)
+        }
+        Err(e) if !can_fall_back(&e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+        Err(_) => {
+            // parent not writable, or the file is a bind mount / on another device than its
+            // directory: stage in the temp dir and copy over the file in place instead
+            let _ = fs::remove_file(&tmp);
+            let staged = std::env::temp_dir().join(&tmp_name);
+            let res = write_temp(&staged, path, &data).and_then(|_| fs::copy(&staged, path));
+            let _ = fs::remove_file(&staged);
+            res.map(|_| false)
         }
-        Some((n, n.min(nlines)))
     }
 }
 
-// ls helpers
+// make the rename itself durable: the new directory entry only survives a crash once the
+// directory is synced. best effort; some filesystems refuse fsync on a directory
 #[cfg(unix)]
-fn perm_string(meta: &Metadata) -> String {
-    let mode = meta.mode();
-    let mut s = String::new();
-    s.push(if meta.is_dir() { 'd' } else { '-' });
-    let bits = [
-        libc::S_IRUSR,
-        libc::S_IWUSR,
-        libc::S_IXUSR,
-        libc::S_IRGRP,
-        libc::S_IWGRP,
-        libc::S_IXGRP,
-        libc::S_IROTH,
-        libc::S_IWOTH,
-        libc::S_IXOTH,
-    ];
-    let chars = ['r', 'w', 'x', 'r', 'w', 'x', 'r', 'w', 'x'];
-    for (b, ch) in bits.iter().zip(chars.iter()) {
-        if (mode & *b as u32) != 0 {
-            s.push(*ch);
-        } else {
-            s.push('-');
+fn sync_dir(dir: &Path) {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) {}
+
+// errors where a rename next to the file can't work but writing the file itself still might
+fn can_fall_back(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        return true;
+    }
+    #[cfg(unix)]
+    if matches!(e.raw_os_error(), Some(libc::EXDEV) | Some(libc::EBUSY)) {
+        return true;
+    }
+    false
+}
+
+// write `data` to `tmp`, synced, with `target`'s mode and owner when it already exists
+fn write_temp(tmp: &Path, target: &Path, data: &[u8]) -> io::Result<()> {
+    #[cfg(unix)]
+    let old_meta = fs::metadata(target).ok();
+    #[cfg(not(unix))]
+    let _ = target;
+
+    #[cfg(unix)]
+    let mut f = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o644)
+    .open(tmp)?;
+
+    #[cfg(not(unix))]
+    let mut f = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .open(tmp)?;
+
+    // the rename replaces the inode, so carry the old file's mode and owner over
+    // (0o644 above only applies to brand-new files)
+    #[cfg(unix)]
+    if let Some(m) = &old_meta {
+        if std::os::unix::fs::fchown(&f, Some(m.uid()), Some(m.gid())).is_err() {
+            // not root: we can still hand it to any group we're in
+            let _ = std::os::unix::fs::fchown(&f, None, Some(m.gid()));
         }
+        // after chown, which may clear setuid/setgid bits
+        f.set_permissions(m.permissions())?;
+    }
+
+    f.write_all(data)?;
+    f.flush()?;
+    f.sync_all()
+}
+
+fn session_dir() -> PathBuf {
+    home_path().join(".trust-sessions")
+}
+
+fn recover_path(path: &Path) -> PathBuf {
+    let mut rec = home_path();
+    let hash = fxhash::hash64(path.to_string_lossy().as_bytes());
+    rec.push(format!(".trust-recover-{:x}", hash));
+    rec
+}
+
+fn recover_is_newer(path: &Path) -> bool {
+    let rec_time = match fs::metadata(recover_path(path)).and_then(|m| m.modified()) {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    match fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(t) => rec_time > t,
+        Err(_) => true,
+    }
+}
+
+fn recover_files() -> Vec<(PathBuf, Metadata)> {
+    let mut out = Vec::new();
+    if let Ok(rd) = fs::read_dir(home_path()) {
+        for e in rd.flatten() {
+            let name = e.file_name().to_string_lossy().to_string();
+            if !name.starts_with(".trust-recover-") {
+                continue;
+            }
+            if let Ok(md) = e.metadata() {
+                if md.is_file() {
+                    out.push((e.path(), md));
+                }
+            }
+        }
+    }
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    out
+}
+
+#[cfg(unix)]
+fn format_time(t: SystemTime) -> String {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as libc::time_t,
+        Err(_) => return "?".to_string(),
+    };
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return "?".to_string();
+        }
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min
+        )
     }
-    s
 }
 
 #[cfg(not(unix))]
-fn perm_string(_meta: &Metadata) -> String {
-    // boring on non unix
-    "----------".to_string()
+fn format_time(t: SystemTime) -> String {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => format!("@{}", d.as_secs()),
+        Err(_) => "?".to_string(),
+    }
 }
 
-fn gradient_str(s: &str, pal: &Palette) -> String {
-    if !use_color() {
-        return s.to_string();
+// first of `names` found in `start` or one of its ancestors
+fn find_up(start: &Path, names: &[&str]) -> Option<PathBuf> {
+    let mut dir = Some(start);
+    while let Some(d) = dir {
+        for n in names {
+            let cand = d.join(n);
+            if cand.is_file() {
+                return Some(cand);
+            }
+        }
+        dir = d.parent();
     }
-    let colors = [pal.title, pal.accent, pal.help_cmd, pal.help_text];
-    let mut out = String::new();
-    for (i, ch) in s.chars().enumerate() {
-        out.push_str(colors[i % colors.len()]);
-        out.push(ch);
+    None
+}
+
+#[derive(Clone, Debug)]
+struct Diagnostic {
+    file: String,
+    line: usize,
+    col: usize,
+    msg: String,
+    // came from a temp copy of the current buffer (rs-run), not a file on disk
+    scratch: bool,
+}
+
+// rustc/cargo diagnostics: "error[E..]: msg" followed by " --> file:line:col",
+// or the one-line "file:line:col: error: msg" from --message-format=short
+fn parse_diagnostics(text: &str) -> Vec<Diagnostic> {
+    fn loc(s: &str) -> Option<(String, usize, usize)> {
+        let mut it = s.rsplitn(3, ':');
+        let col = it.next()?.trim().parse().ok()?;
+        let line = it.next()?.trim().parse().ok()?;
+        let file = it.next()?.trim();
+        if file.is_empty() {
+            return None;
+        }
+        Some((file.to_string(), line, col))
+    }
+    let mut out = Vec::new();
+    let mut msg = String::new();
+    for raw in text.lines() {
+        let l = raw.trim_start();
+        if let Some(rest) = l.strip_prefix("--> ") {
+            if let Some((file, line, col)) = loc(rest) {
+                if !msg.is_empty() {
+                    let msg = msg.clone();
+                    out.push(Diagnostic { file, line, col, msg, scratch: false });
+                }
+            }
+            msg.clear();
+        } else if l.starts_with("error") || l.starts_with("warning") {
+            msg = l.to_string();
+        } else if let Some(idx) = l.find(": error").or_else(|| l.find(": warning")) {
+            if let Some((file, line, col)) = loc(&l[..idx]) {
+                let msg = l[idx + 2..].to_string();
+                out.push(Diagnostic { file, line, col, msg, scratch: false });
+            }
+        }
     }
-    out.push_str("\x1b[0m");
     out
 }
 
-fn gradient_prompt_text(dirty: bool, pal: &Palette) -> String {
-    let base = if dirty { "*trust>" } else { "trust>" };
-    if !use_color() {
-        return format!("{} ", base);
+fn detect_lang_from_path(path: Option<&PathBuf>) -> &'static str {
+    if let Some(p) = path {
+        if let Some(ext) = p.extension().and_then(|e| e.to_str()) {
+            let ext = ext.to_ascii_lowercase();
+            return match ext.as_str() {
+                "rs" => "rust",
+                "c" | "cc" | "cpp" | "cxx" | "h" | "hpp" => "cpp",
+                "py" => "python",
+                "sh" | "bash" | "zsh" => "shell",
+                "js" | "ts" => "js",
+                "html" | "htm" => "html",
+                "css" => "css",
+                "json" => "json",
+                _ => "plain",
+            };
+        }
     }
-    let colors = [pal.title, pal.accent, pal.help_cmd, pal.input];
+    "plain"
+}
+
+// count of Rust-ish signals found in the text (0..=6): fn, let, use, impl, ->, balanced braces
+fn rust_signals(lines: &[String]) -> usize {
+    let has = |pred: &dyn Fn(&str) -> bool| lines.iter().any(|l| pred(l.trim_start()));
+    let mut hits = 0;
+    if has(&|l| l.starts_with("fn ") || l.starts_with("pub fn ") || l.contains(" fn ")) {
+        hits += 1;
+    }
+    if has(&|l| l.starts_with("let ")) {
+        hits += 1;
+    }
+    if has(&|l| l.starts_with("use ") && l.contains("::")) {
+        hits += 1;
+    }
+    if has(&|l| l.starts_with("impl") || l.contains(" impl ")) {
+        hits += 1;
+    }
+    if has(&|l| l.contains("->")) {
+        hits += 1;
+    }
+    let (open, close) = lines.iter().fold((0, 0), |(o, c), l| {
+        (o + l.matches('{').count(), c + l.matches('}').count())
+    });
+    if open > 0 && open == close {
+        hits += 1;
+    }
+    hits
+}
+
+fn looks_like_rust(lines: &[String]) -> bool {
+    rust_signals(lines) >= 3
+}
+
+// every bracket outside strings, char literals and comments as (line, col, ch), 1-based
+fn bracket_tokens(lines: &[String]) -> Vec<(usize, usize, char)> {
+    let mut out = Vec::new();
+    let mut in_str = false;
+    let mut in_block = false;
+    for (li, line) in lines.iter().enumerate() {
+        let cs: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < cs.len() {
+            let c = cs[i];
+            let next = cs.get(i + 1).copied();
+            if in_block {
+                if c == '*' && next == Some('/') {
+                    in_block = false;
+                    i += 1;
+                }
+            } else if in_str {
+                if c == '\\' {
+                    i += 1;
+                } else if c == '"' {
+                    in_str = false;
+                }
+            } else if c == '/' && next == Some('/') {
+                break;
+            } else if c == '/' && next == Some('*') {
+                in_block = true;
+                i += 1;
+            } else if c == '"' {
+                in_str = true;
+            } else if c == '\'' {
+                // 'x' and '\n' are char literals; anything else is a lifetime
+                if next == Some('\\') {
+                    if let Some(end) = cs[i + 2..].iter().position(|&c| c == '\'') {
+                        i += end + 2;
+                    }
+                } else if cs.get(i + 2) == Some(&'\'') {
+                    i += 2;
+                }
+            } else if "()[]{}".contains(c) {
+                out.push((li + 1, i + 1, c));
+            }
+            i += 1;
+        }
+    }
+    out
+}
+
+fn closer_for(open: char) -> char {
+    match open {
+        '(' => ')',
+        '[' => ']',
+        _ => '}',
+    }
+}
+
+// Err((line, ch)) for the first stray closer, or the innermost opener left unclosed
+fn check_balance(lines: &[String]) -> Result<(), (usize, char)> {
+    let mut stack: Vec<(usize, char)> = Vec::new();
+    for (line, _, c) in bracket_tokens(lines) {
+        if "([{".contains(c) {
+            stack.push((line, c));
+        } else {
+            match stack.pop() {
+                Some((_, open)) if closer_for(open) == c => {}
+                _ => return Err((line, c)),
+            }
+        }
+    }
+    match stack.pop() {
+        Some(open) => Err(open),
+        None => Ok(()),
+    }
+}
+
+// tabs -> spaces on tab stops every `width` columns; only in the indent unless `all`
+fn expand_tabs(line: &str, width: usize, all: bool) -> String {
+    let width = width.max(1);
     let mut out = String::new();
-    for (i, ch) in base.chars().enumerate() {
-        out.push_str(colors[i % colors.len()]);
-        out.push(ch);
+    let mut col = 0;
+    let mut leading = true;
+    for c in line.chars() {
+        if c == '\t' && (leading || all) {
+            let n = width - col % width;
+            out.push_str(&" ".repeat(n));
+            col += n;
+            continue;
+        }
+        if c != ' ' && c != '\t' {
+            leading = false;
+        }
+        out.push(c);
+        col += 1;
     }
-    out.push(' ');
-    // don't reset here; linereader will reset after printing input
     out
 }
 
-struct Editor {
-    buf: Buffer,
-    undo: Stack,
-    redo: Stack,
-    others: Vec<Buffer>,
-    theme: Theme,
-    pal: Palette,
-    last_search: String,
-    last_icase: bool,
-    autosave_sec: u64,
-    last_autosave: Instant,
-    aliases: HashMap<String, String>,
-    wrap_long: bool,
-    truncate_long: bool,
-    lr: LineReader,
+// leading whitespace -> as many tabs as fit, then spaces for the remainder
+fn unexpand_tabs(line: &str, width: usize) -> String {
+    let width = width.max(1);
+    let body = line.trim_start_matches([' ', '\t']);
+    let indent = &line[..line.len() - body.len()];
+    let cols = expand_tabs(indent, width, true).len();
+    format!("{}{}{}", "\t".repeat(cols / width), " ".repeat(cols % width), body)
 }
 
-impl Editor {
-    fn new() -> Self {
-        let theme = Theme::Default;
-        let pal = palette_for(theme);
-        let mut lr = LineReader::new();
-        lr.set_commands(&[
-            "help", "open", "info", "write", "w", "wq", "quit", "q", "print", "p", "r", "append",
-            "a", "insert", "i", "delete", "d", "find", "findi", "number", "theme", "alias", "new",
-            "bnext", "bprev", "lsb", "pwd", "cd", "ls", "undo", "u", "redo", "rustfmt", "cargo",
-            "cargo-run", "cargo-check", "cargo-build", "rs-snip", "rs-detect", "rs-explain",
-            "version", "clear", "goto", "rs-run",
-        ]);
-        lr.set_input_color(pal.input);
-        Self {
-            buf: Buffer::new(),
-            undo: Stack::new(),
-            redo: Stack::new(),
-            others: Vec::new(),
-            theme,
-            pal,
-            last_search: String::new(),
-            last_icase: false,
-            autosave_sec: 120,
-            last_autosave: Instant::now(),
-            aliases: HashMap::new(),
-            wrap_long: true,
-            truncate_long: false,
-            lr,
+// partner of the bracket at (line, col) as (line, col); None if it has no partner.
+// a stray closer is skipped and openers left open inside a pair are dropped, so one typo
+// doesn't stop outer pairs from matching.
+fn match_bracket(lines: &[String], line: usize, col: usize) -> Option<(usize, usize)> {
+    let mut stack: Vec<(usize, usize, char)> = Vec::new();
+    for (l, c, ch) in bracket_tokens(lines) {
+        if "([{".contains(ch) {
+            stack.push((l, c, ch));
+            continue;
+        }
+        match stack.iter().rposition(|t| closer_for(t.2) == ch) {
+            Some(i) => {
+                let (ol, oc, _) = stack[i];
+                if stack[i + 1..].iter().any(|t| (t.0, t.1) == (line, col)) {
+                    return None;
+                }
+                stack.truncate(i);
+                if (ol, oc) == (line, col) {
+                    return Some((l, c));
+                }
+                if (l, c) == (line, col) {
+                    return Some((ol, oc));
+                }
+            }
+            None if (l, c) == (line, col) => return None,
+            None => {}
+        }
+    }
+    None
+}
+
+// the first `max - 1` chars of `line` (room for the `…`), or None if it fits in `max`.
+// counts chars, not bytes, so a cut never lands inside a multibyte character
+fn truncate_chars(line: &str, max: usize) -> Option<String> {
+    if line.chars().count() <= max {
+        return None;
+    }
+    Some(line.chars().take(max.saturating_sub(1)).collect())
+}
+
+fn term_width() -> usize {
+    if let Some((w, _)) = term_size::dimensions() {
+        w
+    } else {
+        80
+    }
+}
+
+fn term_height() -> usize {
+    match term_size::dimensions() {
+        Some((_, h)) if h > 2 => h,
+        _ => 24,
+    }
+}
+
+// one keypress without waiting for Enter (falls back to a whole line off-tty)
+#[cfg(unix)]
+fn read_key() -> Option<u8> {
+    use std::os::fd::AsRawFd;
+    let stdin = io::stdin();
+    let fd = stdin.as_raw_fd();
+    let raw = RawModeGuard::new(fd).ok();
+    let mut byte = [0u8; 1];
+    let n = stdin.lock().read(&mut byte);
+    drop(raw);
+    match n {
+        Ok(1) => Some(byte[0]),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn read_key() -> Option<u8> {
+    let mut s = String::new();
+    io::stdin().read_line(&mut s).ok()?;
+    Some(s.bytes().next().unwrap_or(b'\n'))
+}
+
+// a keypress if one arrives within `ms`; Some(0) when stdin is closed (so loops stop)
+#[cfg(unix)]
+fn poll_key(ms: i32) -> Option<u8> {
+    use std::os::fd::AsRawFd;
+    let stdin = io::stdin();
+    let fd = stdin.as_raw_fd();
+    let raw = RawModeGuard::new(fd).ok();
+    let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let ready = unsafe { libc::poll(&mut pfd, 1, ms) };
+    let key = if ready > 0 {
+        let mut byte = [0u8; 1];
+        match stdin.lock().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => Some(0),
+        }
+    } else {
+        None
+    };
+    drop(raw);
+    key
+}
+
+#[cfg(not(unix))]
+fn poll_key(ms: i32) -> Option<u8> {
+    std::thread::sleep(Duration::from_millis(ms as u64));
+    None
+}
+
+// shell-style `*` / `?` match against a whole file name
+fn glob_match(pat: &str, name: &str) -> bool {
+    let p: Vec<char> = pat.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // where the last `*` was, and how much of the name it had swallowed
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+// None unless every query char appears in order (case-insensitive); higher is better.
+// consecutive runs and hits right after a separator ('/', '_', '-', '.') score extra,
+// gaps and long paths cost a little
+fn fuzzy_score(candidate: &str, query: &str) -> Option<i32> {
+    let cand: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut score = 0i32;
+    let mut ci = 0;
+    let mut prev: Option<usize> = None;
+    for q in query.to_lowercase().chars() {
+        let at = (ci..cand.len()).find(|&i| cand[i] == q)?;
+        score += 10;
+        match prev {
+            Some(p) if p + 1 == at => score += 15,
+            Some(p) => score -= (at - p - 1).min(10) as i32,
+            None => {}
+        }
+        if at == 0 || matches!(cand[at - 1], '/' | '_' | '-' | '.') {
+            score += 20;
+        }
+        prev = Some(at);
+        ci = at + 1;
+    }
+    Some(score - (cand.len() / 8) as i32)
+}
+
+const WALK_MAX_DEPTH: usize = 16;
+const WALK_MAX_FILES: usize = 20_000;
+const GREP_MAX_HITS: usize = 500;
+
+// one `.gitignore` line, compiled. `anchored` patterns (a `/` anywhere but the end) match the
+// path from the project root, the rest match any single file or directory name
+struct IgnoreRule {
+    re: regex::Regex,
+    anchored: bool,
+    negate: bool,
+    dir_only: bool,
+}
+
+fn parse_ignore_line(line: &str) -> Option<IgnoreRule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negate, mut pat) = match line.strip_prefix('!') {
+        Some(p) => (true, p),
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let dir_only = pat.ends_with('/');
+    pat = pat.trim_end_matches('/');
+    let anchored = pat.contains('/');
+    pat = pat.trim_start_matches('/');
+    if pat.is_empty() {
+        return None;
+    }
+    let mut re = String::from("^");
+    let c: Vec<char> = pat.chars().collect();
+    let mut i = 0;
+    while i < c.len() {
+        match c[i] {
+            '*' if c.get(i + 1) == Some(&'*') => {
+                if c.get(i + 2) == Some(&'/') {
+                    re.push_str("(?:.*/)?");
+                    i += 1;
+                } else {
+                    re.push_str(".*");
+                }
+                i += 1;
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => match c[i..].iter().position(|&x| x == ']') {
+                Some(end) if end > 1 => {
+                    let class: String = c[i + 1..i + end].iter().collect();
+                    let class = class.replacen('!', "^", usize::from(class.starts_with('!')));
+                    re.push('[');
+                    re.push_str(&class.replace('\\', "\\\\"));
+                    re.push(']');
+                    i += end;
+                }
+                _ => re.push_str("\\["),
+            },
+            ch => re.push_str(&regex::escape(&ch.to_string())),
+        }
+        i += 1;
+    }
+    re.push('$');
+    let re = regex::Regex::new(&re).ok()?;
+    Some(IgnoreRule { re, anchored, negate, dir_only })
+}
+
+// `rel` is relative to the project root, `/`-separated. later rules win, like git
+fn is_ignored(rules: &[IgnoreRule], rel: &str, is_dir: bool) -> bool {
+    let name = rel.rsplit('/').next().unwrap_or(rel);
+    let mut ignored = false;
+    for r in rules {
+        if r.dir_only && !is_dir {
+            continue;
+        }
+        if r.re.is_match(if r.anchored { rel } else { name }) {
+            ignored = !r.negate;
+        }
+    }
+    ignored
+}
+
+// the nearest directory at or above `start` holding `.git`; `start` itself if there's none
+fn project_root(start: &Path) -> PathBuf {
+    let abs = fs::canonicalize(start).unwrap_or_else(|_| start.to_path_buf());
+    for dir in abs.ancestors() {
+        if dir.join(".git").exists() {
+            return dir.to_path_buf();
+        }
+    }
+    abs
+}
+
+// lazy, name-ordered walk behind `walk_project`
+struct ProjectWalk {
+    // paths still to visit (popped from the end), with their depth and whether they're dirs
+    stack: Vec<(PathBuf, usize, bool)>,
+    rules: Vec<IgnoreRule>,
+    // the walk root as given and made absolute, then the project root; together they turn
+    // a walked path into the root-relative form .gitignore patterns are written against
+    root: PathBuf,
+    abs_root: PathBuf,
+    proj: PathBuf,
+}
+
+impl ProjectWalk {
+    fn rel(&self, path: &Path) -> String {
+        let full = self.abs_root.join(path.strip_prefix(&self.root).unwrap_or(path));
+        full.strip_prefix(&self.proj).unwrap_or(&full).to_string_lossy().to_string()
+    }
+
+    fn push_dir(&mut self, dir: &Path, depth: usize) {
+        let mut entries: Vec<_> = match fs::read_dir(dir) {
+            Ok(rd) => rd.flatten().collect(),
+            Err(_) => return,
+        };
+        entries.sort_by_key(|e| e.file_name());
+        for e in entries.into_iter().rev() {
+            let name = e.file_name().to_string_lossy().to_string();
+            let is_dir = match e.file_type() {
+                Ok(ft) => ft.is_dir(),
+                Err(_) => continue,
+            };
+            let skip_dir = name.starts_with('.') || name == "target" || depth + 1 >= WALK_MAX_DEPTH;
+            if is_dir && skip_dir {
+                continue;
+            }
+            let path = e.path();
+            if is_ignored(&self.rules, &self.rel(&path), is_dir) {
+                continue;
+            }
+            self.stack.push((path, depth + 1, is_dir));
+        }
+    }
+}
+
+impl Iterator for ProjectWalk {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        while let Some((path, depth, is_dir)) = self.stack.pop() {
+            if !is_dir {
+                return Some(path);
+            }
+            self.push_dir(&path, depth);
+        }
+        None
+    }
+}
+
+// files under `root` in path order, skipping dot-dirs, `target/`, and whatever the project's
+// top-level `.gitignore` lists (the project being the enclosing git checkout, else `root`)
+fn walk_project(root: &Path) -> impl Iterator<Item = PathBuf> {
+    let abs_root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let proj = project_root(root);
+    let rules = fs::read_to_string(proj.join(".gitignore"))
+    .map(|t| t.lines().filter_map(parse_ignore_line).collect())
+    .unwrap_or_default();
+    let root = root.to_path_buf();
+    let stack = vec![(root.clone(), 0, true)];
+    ProjectWalk { stack, rules, root, abs_root, proj }
+}
+
+// walk_project, capped at WALK_MAX_FILES; the bool says whether it stopped early
+fn walk_files(root: &Path) -> (Vec<PathBuf>, bool) {
+    let mut out: Vec<PathBuf> = walk_project(root).take(WALK_MAX_FILES + 1).collect();
+    let truncated = out.len() > WALK_MAX_FILES;
+    out.truncate(WALK_MAX_FILES);
+    (out, truncated)
+}
+
+// `$1`..`$9` and `$*` take the typed arguments; with no placeholders they're appended
+fn expand_alias(exp: &str, rest: &str) -> String {
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    let mut out = String::new();
+    let mut used = false;
+    let mut it = exp.chars().peekable();
+    while let Some(c) = it.next() {
+        match (c, it.peek().copied()) {
+            ('$', Some(d @ '1'..='9')) => {
+                it.next();
+                let n = d as usize - '1' as usize;
+                out.push_str(args.get(n).copied().unwrap_or(""));
+                used = true;
+            }
+            ('$', Some('*')) => {
+                it.next();
+                out.push_str(&args.join(" "));
+                used = true;
+            }
+            _ => out.push(c),
+        }
+    }
+    if !used && !rest.is_empty() {
+        out.push(' ');
+        out.push_str(rest);
+    }
+    out
+}
+
+// leading -C<n> / -A<n> / -B<n> flags of `find`; returns ((before, after), rest)
+fn parse_context_flags(s: &str) -> ((usize, usize), &str) {
+    let mut ctx = (0, 0);
+    let mut rest = s.trim_start();
+    while let Some(flag) = rest.split_whitespace().next() {
+        let n = match flag.get(2..).map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) if flag.len() > 2 => n,
+            _ => break,
+        };
+        match &flag[..2] {
+            "-C" => ctx = (n, n),
+            "-A" => ctx.1 = n,
+            "-B" => ctx.0 = n,
+            _ => break,
+        }
+        rest = rest[flag.len()..].trim_start();
+    }
+    (ctx, rest)
+}
+
+fn parse_range(s: &str, nlines: usize) -> Option<(usize, usize)> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Some((1, nlines));
+    }
+    if let Some(idx) = s.find('-') {
+        let left = &s[..idx];
+        let right = &s[idx + 1..];
+        let lo = if left.is_empty() {
+            1
+        } else {
+            left.parse::<usize>().ok()?
+        };
+        let hi = if right.is_empty() {
+            nlines
+        } else {
+            right.parse::<usize>().ok()?
+        };
+        if lo == 0 || hi == 0 || lo > hi {
+            return None;
+        }
+        Some((lo, hi.min(nlines)))
+    } else {
+        let n = s.parse::<usize>().ok()?;
+        if n == 0 {
+            return None;
+        }
+        Some((n, n.min(nlines)))
+    }
+}
+
+fn reflow_lines(lines: &[String], width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].trim().is_empty() {
+            out.push(String::new());
+            i += 1;
+            continue;
+        }
+        // paragraph: keep the first line's indentation for every wrapped row
+        let indent: String = lines[i].chars().take_while(|c| c.is_whitespace()).collect();
+        let mut words: Vec<&str> = Vec::new();
+        while i < lines.len() && !lines[i].trim().is_empty() {
+            words.extend(lines[i].split_whitespace());
+            i += 1;
+        }
+        let mut cur = indent.clone();
+        for w in words {
+            if cur.len() > indent.len() && cur.len() + 1 + w.len() > width {
+                out.push(cur);
+                cur = indent.clone();
+            }
+            if cur.len() > indent.len() {
+                cur.push(' ');
+            }
+            cur.push_str(w);
+        }
+        out.push(cur);
+    }
+    out
+}
+
+// single address: N, ".", "$", optionally followed by +N/-N offsets ("+3" alone is ".+3")
+fn parse_addr(s: &str, nlines: usize, cur: usize) -> Option<usize> {
+    let s = s.trim();
+    let (mut n, mut tail) = match s.chars().next()? {
+        '.' => (cur as i64, &s[1..]),
+        '$' => (nlines as i64, &s[1..]),
+        '+' | '-' => (cur as i64, s),
+        c if c.is_ascii_digit() => {
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            (s[..end].parse::<i64>().ok()?, &s[end..])
+        }
+        _ => return None,
+    };
+    while let Some(sign) = tail.chars().next() {
+        let rest = &tail[1..];
+        let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let off = if end == 0 { 1 } else { rest[..end].parse::<i64>().ok()? };
+        n = match sign {
+            '+' => n + off,
+            '-' => n - off,
+            _ => return None,
+        };
+        tail = &rest[end..];
+    }
+    if n < 0 {
+        return None;
+    }
+    Some(n as usize)
+}
+
+// parse_range plus ed-style addresses: ".,+2", "$-5,$", ".", "+3", "%" (whole file).
+// plain "lo-hi" / "-hi" / "lo-" keep their old meaning, so a bare "-3" is lines 1-3 here
+// even though `goto -3` (a single address) moves up three; ".-3" is the relative range.
+fn parse_range_at(s: &str, nlines: usize, cur: usize) -> Option<(usize, usize)> {
+    let s = s.trim();
+    if s == "%" {
+        return if nlines == 0 { None } else { Some((1, nlines)) };
+    }
+    let legacy = s.chars().all(|c| c.is_ascii_digit() || c == '-') && s.matches('-').count() <= 1;
+    let (lo, hi) = if legacy {
+        parse_range(s, nlines)?
+    } else if let Some((a, b)) = s.split_once(',') {
+        let lo = if a.trim().is_empty() { 1 } else { parse_addr(a, nlines, cur)? };
+        let hi = if b.trim().is_empty() { nlines } else { parse_addr(b, nlines, cur)? };
+        (lo, hi.min(nlines))
+    } else {
+        let n = parse_addr(s, nlines, cur)?;
+        (n, n.min(nlines))
+    };
+    if lo == 0 || lo > hi {
+        return None;
+    }
+    Some((lo, hi))
+}
+
+// "src/main.rs:120", "src/main.rs:120:5" or grep's "src/main.rs:120:text" -> path + line.
+// the line is the first all-digit field after a `:`, so text after it may hold colons too;
+// if several splits qualify, one naming a file that exists wins
+fn split_path_line(arg: &str) -> (String, Option<usize>) {
+    if Path::new(arg).exists() {
+        return (arg.to_string(), None);
+    }
+    let mut first = None;
+    for (i, _) in arg.match_indices(':') {
+        let (path, tail) = (&arg[..i], &arg[i + 1..]);
+        let num = tail.split(':').next().unwrap_or("");
+        if path.is_empty() || num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let n = num.parse().ok();
+        if Path::new(path).exists() {
+            return (path.to_string(), n);
+        }
+        first.get_or_insert((path.to_string(), n));
+    }
+    first.unwrap_or_else(|| (arg.to_string(), None))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum DiffTag {
+    Same,
+    Del,
+    Add,
+}
+
+// past this many LCS cells the changed middle is reported as one big replace
+const DIFF_MAX_CELLS: usize = 4_000_000;
+
+// line diff via LCS, after trimming the common head/tail
+fn diff_lines<'a>(a: &'a [String], b: &'a [String]) -> Vec<(DiffTag, &'a str)> {
+    let mut pre = 0;
+    while pre < a.len() && pre < b.len() && a[pre] == b[pre] {
+        pre += 1;
+    }
+    let mut suf = 0;
+    while suf < a.len() - pre
+        && suf < b.len() - pre
+        && a[a.len() - 1 - suf] == b[b.len() - 1 - suf]
+    {
+        suf += 1;
+    }
+    let am = &a[pre..a.len() - suf];
+    let bm = &b[pre..b.len() - suf];
+    let mut out: Vec<(DiffTag, &str)> =
+        a[..pre].iter().map(|l| (DiffTag::Same, l.as_str())).collect();
+
+    if am.len() * bm.len() > DIFF_MAX_CELLS {
+        out.extend(am.iter().map(|l| (DiffTag::Del, l.as_str())));
+        out.extend(bm.iter().map(|l| (DiffTag::Add, l.as_str())));
+    } else {
+        // lcs[i][j] = LCS length of am[i..] and bm[j..]
+        let w = bm.len() + 1;
+        let mut lcs = vec![0u32; (am.len() + 1) * w];
+        for i in (0..am.len()).rev() {
+            for j in (0..bm.len()).rev() {
+                lcs[i * w + j] = if am[i] == bm[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < am.len() && j < bm.len() {
+            if am[i] == bm[j] {
+                out.push((DiffTag::Same, &am[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * w + j] >= lcs[i * w + j + 1] {
+                out.push((DiffTag::Del, &am[i]));
+                i += 1;
+            } else {
+                out.push((DiffTag::Add, &bm[j]));
+                j += 1;
+            }
+        }
+        out.extend(am[i..].iter().map(|l| (DiffTag::Del, l.as_str())));
+        out.extend(bm[j..].iter().map(|l| (DiffTag::Add, l.as_str())));
+    }
+
+    out.extend(a[a.len() - suf..].iter().map(|l| (DiffTag::Same, l.as_str())));
+    out
+}
+
+// the file as of HEAD, plus gutter marks and the hash of the lines they were computed for
+#[derive(Clone)]
+struct GitCache {
+    // None when the file isn't inside a git checkout
+    head: Option<Vec<String>>,
+    key: u64,
+    marks: Vec<char>,
+}
+
+// HEAD's copy of `path`: None outside a repo, empty when git doesn't track it (yet)
+fn git_head_lines(path: &Path) -> Option<Vec<String>> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let out = Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .arg("show")
+    .arg(format!("HEAD:./{}", name))
+    .stdin(Stdio::null())
+    .output()
+    .ok()?;
+    if out.status.success() {
+        return Some(String::from_utf8_lossy(&out.stdout).lines().map(str::to_string).collect());
+    }
+    let err = String::from_utf8_lossy(&out.stderr);
+    if err.contains("not a git repository") {
+        None
+    } else {
+        Some(Vec::new())
+    }
+}
+
+// what `git blame --porcelain` says about one line
+struct Blame {
+    commit: String,
+    author: String,
+    mail: String,
+    time: u64,
+    summary: String,
+}
+
+fn parse_blame(porcelain: &str) -> Option<Blame> {
+    let mut lines = porcelain.lines();
+    let commit = lines.next()?.split_whitespace().next()?.to_string();
+    let mut b = Blame {
+        commit,
+        author: String::new(),
+        mail: String::new(),
+        time: 0,
+        summary: String::new(),
+    };
+    // headers run until the tab-prefixed copy of the line itself
+    for l in lines.take_while(|l| !l.starts_with('\t')) {
+        let (k, v) = l.split_once(' ').unwrap_or((l, ""));
+        match k {
+            "author" => b.author = v.to_string(),
+            "author-mail" => b.mail = v.to_string(),
+            "author-time" => b.time = v.parse().unwrap_or(0),
+            "summary" => b.summary = v.to_string(),
+            _ => {}
+        }
+    }
+    Some(b)
+}
+
+// one mark per buffer line: `+` added, `~` changed, `-` lines were removed just above
+// (or below, at the end of the file), ' ' untouched
+fn gutter_marks(head: &[String], lines: &[String]) -> Vec<char> {
+    let mut marks = vec![' '; lines.len()];
+    let ops = diff_lines(head, lines);
+    let (mut k, mut j) = (0, 0);
+    while k < ops.len() {
+        if ops[k].0 == DiffTag::Same {
+            k += 1;
+            j += 1;
+            continue;
+        }
+        let dels = ops[k..].iter().take_while(|o| o.0 == DiffTag::Del).count();
+        k += dels;
+        let adds = ops[k..].iter().take_while(|o| o.0 == DiffTag::Add).count();
+        k += adds;
+        for n in 0..adds {
+            marks[j + n] = if n < dels { '~' } else { '+' };
+        }
+        j += adds;
+        if dels > adds && !marks.is_empty() {
+            let at = j.min(marks.len() - 1);
+            if marks[at] == ' ' {
+                marks[at] = '-';
+            }
+        }
+    }
+    marks
+}
+
+// ls helpers
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // mode_t is u16 on some BSDs
+fn perm_string(meta: &Metadata) -> String {
+    let mode = meta.mode();
+    let mut s = String::new();
+    let ft = meta.file_type();
+    s.push(if ft.is_symlink() {
+        'l'
+    } else if ft.is_dir() {
+        'd'
+    } else {
+        '-'
+    });
+    let bits = [
+        libc::S_IRUSR,
+        libc::S_IWUSR,
+        libc::S_IXUSR,
+        libc::S_IRGRP,
+        libc::S_IWGRP,
+        libc::S_IXGRP,
+        libc::S_IROTH,
+        libc::S_IWOTH,
+        libc::S_IXOTH,
+    ];
+    let chars = ['r', 'w', 'x', 'r', 'w', 'x', 'r', 'w', 'x'];
+    for (b, ch) in bits.iter().zip(chars.iter()) {
+        if (mode & *b as u32) != 0 {
+            s.push(*ch);
+        } else {
+            s.push('-');
+        }
+    }
+    s
+}
+
+#[cfg(not(unix))]
+fn perm_string(_meta: &Metadata) -> String {
+    // boring on non unix
+    "----------".to_string()
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &Metadata) -> bool {
+    meta.is_file() && meta.mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &Metadata) -> bool {
+    false
+}
+
+// user and group names for ls -l, falling back to the numeric ids
+#[cfg(unix)]
+fn owner_names(meta: &Metadata) -> (String, String) {
+    let (uid, gid) = (meta.uid(), meta.gid());
+    unsafe {
+        let pw = libc::getpwuid(uid);
+        let user = if pw.is_null() {
+            uid.to_string()
+        } else {
+            std::ffi::CStr::from_ptr((*pw).pw_name).to_string_lossy().to_string()
+        };
+        let gr = libc::getgrgid(gid);
+        let group = if gr.is_null() {
+            gid.to_string()
+        } else {
+            std::ffi::CStr::from_ptr((*gr).gr_name).to_string_lossy().to_string()
+        };
+        (user, group)
+    }
+}
+
+#[cfg(not(unix))]
+fn owner_names(_meta: &Metadata) -> (String, String) {
+    ("-".to_string(), "-".to_string())
+}
+
+// perms owner group size mtime name [-> target]; `meta` should come from symlink_metadata
+fn ls_long_line(meta: &Metadata, shown: &str, full: &Path, human: bool) -> String {
+    let (user, group) = owner_names(meta);
+    let size = if human { human_size(meta.len()) } else { meta.len().to_string() };
+    let mtime = meta.modified().map(format_time).unwrap_or_else(|_| "?".to_string());
+    let mut line = format!(
+        "{:10} {:<8} {:<8} {:>8}  {}  {}",
+        perm_string(meta),
+        user,
+        group,
+        size,
+        mtime,
+        shown
+    );
+    if meta.file_type().is_symlink() {
+        if let Ok(target) = fs::read_link(full) {
+            line.push_str(&format!(" -> {}", target.display()));
+        }
+    }
+    line
+}
+
+// 1536 -> "1.5K", like ls -h
+fn human_size(n: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if n < 1024 {
+        return n.to_string();
+    }
+    let mut v = n as f64 / 1024.0;
+    let mut u = 0;
+    while v >= 1024.0 && u + 1 < UNITS.len() {
+        v /= 1024.0;
+        u += 1;
+    }
+    if v < 10.0 {
+        format!("{:.1}{}", v, UNITS[u])
+    } else {
+        format!("{:.0}{}", v, UNITS[u])
+    }
+}
+
+fn gradient_str(s: &str, pal: &Palette) -> String {
+    if !use_color() {
+        return s.to_string();
+    }
+    let colors = [pal.title, pal.accent, pal.help_cmd, pal.help_text];
+    let mut out = String::new();
+    for (i, ch) in s.chars().enumerate() {
+        out.push_str(colors[i % colors.len()]);
+        out.push(ch);
+    }
+    out.push_str("\x1b[0m");
+    out
+}
+
+// `set prompt_format`: %f file, %l line count, %m dirty marker, %d cwd basename, %t theme, %%;
+// None on an unknown or dangling %, so the caller can fall back to the stock prompt
+fn expand_prompt_format(fmt: &str, buf: &Buffer, theme: Theme) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'f' => match buf.path.as_ref().and_then(|p| p.file_name()) {
+                Some(n) => out.push_str(&n.to_string_lossy()),
+                None => out.push_str("(unnamed)"),
+            },
+            'l' => out.push_str(&buf.lines.len().to_string()),
+            'm' => out.push_str(if buf.dirty { "*" } else { "" }),
+            'd' => {
+                let cwd = std::env::current_dir().unwrap_or_default();
+                match cwd.file_name() {
+                    Some(n) => out.push_str(&n.to_string_lossy()),
+                    None => out.push('/'),
+                }
+            }
+            't' => out.push_str(&lower(&format!("{:?}", theme))),
+            '%' => out.push('%'),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+fn gradient_prompt_text(base: &str, pal: &Palette) -> String {
+    if !use_color() {
+        return format!("{} ", base);
+    }
+    let colors = [pal.title, pal.accent, pal.help_cmd, pal.input];
+    let mut out = String::new();
+    for (i, ch) in base.chars().enumerate() {
+        out.push_str(colors[i % colors.len()]);
+        out.push(ch);
+    }
+    out.push(' ');
+    // don't reset here; linereader will reset after printing input
+    out
+}
+
+// `set prompt plain`: one color, for people (and screen readers) the gradient bothers
+fn plain_prompt_text(base: &str, pal: &Palette) -> String {
+    // no reset, same as the gradient: the line reader takes over the color for input
+    format!("{}{} ", pal.prompt, base)
+}
+
+struct Editor {
+    buf: Buffer,
+    others: Vec<Buffer>,
+    theme: Theme,
+    pal: Palette,
+    last_search: String,
+    // `nohl`: hide hlsearch highlighting until the next search
+    nohl: bool,
+    last_icase: bool,
+    last_regex: bool,
+    autosave_sec: u64,
+    last_autosave: Instant,
+    aliases: HashMap<String, String>,
+    wrap_long: bool,
+    truncate_long: bool,
+    indent_width: usize,
+    cur_cmd: String,
+    rustfmt_confirm: bool,
+    edition: String,
+    pager: bool,
+    trim_on_save: bool,
+    light_bg: bool,
+    prompt_style: String,
+    prompt_format: String,
+    dict: String,
+    macros: HashMap<char, Vec<String>>,
+    last_cmd: Option<String>,
+    recording: Option<char>,
+    // nesting depth of `play`, so replayed lines aren't recorded and recursion stops
+    playing: usize,
+    // text lines a replayed append/insert reads instead of the keyboard
+    replay_text: VecDeque<String>,
+    backup: BackupOpts,
+    diags: Vec<Diagnostic>,
+    diag_idx: usize,
+    last_run_hash: Option<u64>,
+    bar_rows: usize,
+    gitgutter: bool,
+    // the lines may have changed since git_mark last hashed them; set per command, per edit
+    // and per print_range/show_view so a screenful of marks costs one hash, not one per line
+    git_stale: Cell<bool>,
+    // pushd/popd, and where `cd -` goes
+    dir_stack: Vec<PathBuf>,
+    prev_dir: Option<PathBuf>,
+    lr: LineReader,
+}
+
+impl Editor {
+    fn new() -> Self {
+        let theme = Theme::Default;
+        let pal = palette_for(theme, false);
+        let mut lr = LineReader::new();
+        lr.set_commands(&[
+            "help", "open", "info", "write", "w", "wq", "quit", "q", "print", "p", "r", "append",
+            "a", "insert", "i", "delete", "d", "find", "findi", "number", "theme", "alias", "new",
+            "bnext", "bprev", "lsb", "pwd", "cd", "ls", "undo", "u", "redo", "rustfmt", "cargo",
+            "cargo-run", "cargo-check", "cargo-build", "rs-snip", "rs-detect", "rs-explain",
+            "version", "clear", "goto", "rs-run", "set", "reload", "recover",
+            "recover-list", "recover-clean", "reflow", "findre", "findrei",
+            "count", "where", "mark", "open!", "wqa", "buffer", "close",
+            "bdelete", "cerr", "rs-check", "balance", "matchbracket",
+            "json-fmt", "json-check", "expand", "unexpand", "trim",
+            "hex", "pipe", "readcmd", "global", "globalv",
+            "unalias", "ffind", "fuzzy", "grep",
+            "mkdir", "rm", "mv", "cp", "create",
+            "view", "next", "prev", "head", "tail", "follow", "nohl",
+            "spell", "saveas", "diff", "revert", "record", "stop", "play",
+            "repeat", "session", "blame", "pushd", "popd", "dirs",
+        ]);
+        lr.set_input_color(pal.input);
+        lr.set_arg_words(&["theme"], THEME_NAMES);
+        lr.set_arg_words(&["set"], SET_KEYS);
+        let snips = ["main", "mod", "test", "struct", "enum", "trait", "derive"];
+        lr.set_arg_words(&["rs-snip"], &snips);
+        Self {
+            buf: Buffer::new(),
+            others: Vec::new(),
+            theme,
+            pal,
+            last_search: String::new(),
+            nohl: false,
+            last_icase: false,
+            last_regex: false,
+            autosave_sec: 120,
+            last_autosave: Instant::now(),
+            aliases: HashMap::new(),
+            wrap_long: true,
+            truncate_long: false,
+            indent_width: 4,
+            cur_cmd: String::new(),
+            rustfmt_confirm: false,
+            edition: "2021".to_string(),
+            pager: false,
+            trim_on_save: false,
+            light_bg: false,
+            prompt_style: "gradient".to_string(),
+            prompt_format: String::new(),
+            dict: "/usr/share/dict/words".to_string(),
+            macros: HashMap::new(),
+            last_cmd: None,
+            recording: None,
+            playing: 0,
+            replay_text: VecDeque::new(),
+            backup: BackupOpts { ext: "~".to_string(), dir: None, keep: 1 },
+            diags: Vec::new(),
+            diag_idx: 0,
+            last_run_hash: None,
+            gitgutter: false,
+            git_stale: Cell::new(true),
+            dir_stack: Vec::new(),
+            prev_dir: None,
+            bar_rows: 0,
+            lr,
+        }
+    }
+
+    fn prompt(&self) -> String {
+        let stock = if self.buf.dirty { "*trust>" } else { "trust>" };
+        let base = match expand_prompt_format(&self.prompt_format, &self.buf, self.theme) {
+            Some(b) if !b.trim().is_empty() => b.trim_end().to_string(),
+            _ => stock.to_string(),
+        };
+        if self.prompt_style == "plain" {
+            plain_prompt_text(&base, &self.pal)
+        } else {
+            gradient_prompt_text(&base, &self.pal)
+        }
+    }
+
+    fn status_text(&self) -> String {
+        let lang = detect_lang_from_path(self.buf.path.as_ref());
+        let mut text = format!(
+            "[{}{}]{} line={} lines={} chars={} lang={} {}{} {} theme={:?} wrap:{}",
+            self.buf.name(),
+            if self.buf.dirty { " *" } else { "" },
+            if self.buf.readonly { " ro" } else { "" },
+            self.current_line(),
+            self.buf.lines.len(),
+            self.buf.char_count(),
+            lang,
+            if self.buf.crlf { "crlf" } else { "lf" },
+            if self.buf.final_newline { "" } else { "(noeol)" },
+            self.buf.encoding,
+            self.theme,
+            if self.wrap_long { "on" } else { "off" }
+        );
+        if let Some(reg) = self.recording {
+            text.push_str(&format!(" recording:{}", reg));
+        }
+        text
+    }
+
+    fn status(&self) {
+        println!("{}{}{}\x1b[0m", self.pal.dim, self.status_text(), "");
+    }
+
+    // pin the status to the bottom row: a scroll region keeps everything else above it,
+    // so scrollback doesn't collect a copy per command. off a tty, print it like before
+    fn draw_status_bar(&mut self) {
+        if !atty::is(Stream::Stdout) {
+            self.status();
+            return;
+        }
+        let h = term_height();
+        if h != self.bar_rows {
+            // make room first: setting the region homes the cursor, and we put it back
+            // on a row that must lie inside the region
+            print!("\n\x1b[1A\x1b7\x1b[1;{}r\x1b8", h - 1);
+            self.bar_rows = h;
+        }
+        let text: String = self.status_text().chars().take(term_width()).collect();
+        let style = if use_color() { "\x1b[7m" } else { "" };
+        print!("\x1b7\x1b[{};1H\x1b[2K{}{}\x1b[0m\x1b8", h, style, text);
+        let _ = io::stdout().flush();
+    }
+
+    fn drop_status_bar(&mut self) {
+        if self.bar_rows == 0 {
+            return;
+        }
+        print!("\x1b7\x1b[r\x1b[{};1H\x1b[2K\x1b8", self.bar_rows);
+        let _ = io::stdout().flush();
+        self.bar_rows = 0;
+    }
+
+    // tracked line, clamped to the buffer (0 only when the buffer is empty)
+    fn current_line(&self) -> usize {
+        self.buf.cursor_line.clamp(1, self.buf.lines.len().max(1)).min(self.buf.lines.len())
+    }
+
+    // swap 'x mark references for their line numbers; None if a mark is unset
+    fn resolve_marks(&self, s: &str) -> Option<String> {
+        let mut out = String::new();
+        let mut it = s.chars();
+        while let Some(c) = it.next() {
+            if c == '\'' {
+                let name = it.next()?;
+                out.push_str(&self.buf.marks.get(&name)?.to_string());
+            } else {
+                out.push(c);
+            }
+        }
+        Some(out)
+    }
+
+    fn range(&self, s: &str) -> Option<(usize, usize)> {
+        let s = self.resolve_marks(s)?;
+        parse_range_at(&s, self.buf.lines.len(), self.current_line())
+    }
+
+    fn addr(&self, s: &str) -> Option<usize> {
+        let s = self.resolve_marks(s)?;
+        parse_addr(&s, self.buf.lines.len(), self.current_line())
+    }
+
+    // lines at..at+removed-1 were replaced by `added` lines; keep marks pointing at the same text
+    fn shift_marks(&mut self, at: usize, removed: usize, added: usize) {
+        self.buf.marks.retain(|_, m| !(added == 0 && *m >= at && *m < at + removed));
+        for m in self.buf.marks.values_mut() {
+            if *m >= at + removed {
+                *m = *m + added - removed;
+            } else if *m >= at && *m >= at + added {
+                *m = at + added - 1;
+            }
+        }
+    }
+
+    fn cmd_mark(&mut self, args: &str) {
+        if args.is_empty() {
+            if self.buf.marks.is_empty() {
+                println!("no marks set");
+            }
+            let mut v: Vec<_> = self.buf.marks.iter().collect();
+            v.sort();
+            for (name, line) in v {
+                println!("  '{} {}", name, line);
+            }
+            return;
+        }
+        let mut chars = args.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => {
+                let cur = self.current_line();
+                if cur == 0 {
+                    println!("(empty)");
+                    return;
+                }
+                self.buf.marks.insert(c, cur);
+                println!("mark '{} = line {}", c, cur);
+            }
+            _ => println!("{}usage: mark <letter>{}\x1b[0m", self.pal.warn, ""),
+        }
+    }
+
+    // `matchbracket <line> [col]`; without a column the first bracket on the line is used
+    fn cmd_matchbracket(&mut self, args: &str) {
+        let mut it = args.split_whitespace();
+        let line = match it.next().and_then(|a| self.addr(a)) {
+            Some(n) if n >= 1 && n <= self.buf.lines.len() => n,
+            _ => {
+                println!("{}usage: matchbracket <line> [col]{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let toks = bracket_tokens(&self.buf.lines);
+        let col = match it.next() {
+            Some(c) => match c.parse::<usize>() {
+                Ok(c) => c,
+                Err(_) => {
+                    println!("{}bad column{}\x1b[0m", self.pal.warn, "");
+                    return;
+                }
+            },
+            None => match toks.iter().find(|t| t.0 == line) {
+                Some(t) => t.1,
+                None => {
+                    println!("{}no bracket on line {}{}\x1b[0m", self.pal.warn, line, "");
+                    return;
+                }
+            },
+        };
+        let ch = match toks.iter().find(|t| t.0 == line && t.1 == col) {
+            Some(t) => t.2,
+            None => {
+                println!("{}no bracket at {}:{}{}\x1b[0m", self.pal.warn, line, col, "");
+                return;
+            }
+        };
+        match match_bracket(&self.buf.lines, line, col) {
+            Some((l, c)) => {
+                let w = digits_for(self.buf.lines.len());
+                println!("'{}' at {}:{} matches {}:{}", ch, line, col, l, c);
+                println!("{:>w$} | {}", l, self.buf.lines[l - 1], w = w);
+                self.set_line(l);
+            }
+            None => {
+                println!("{}'{}' at {}:{} has no match{}\x1b[0m", self.pal.warn, ch, line, col, "")
+            }
+        }
+    }
+
+    fn set_line(&mut self, n: usize) {
+        self.buf.cursor_line = n;
+        self.buf.cursor_line = self.current_line();
+    }
+
+    fn cmd_where(&self) {
+        let total = self.buf.lines.len();
+        let cur = self.current_line();
+        let pct = (cur * 100).checked_div(total).unwrap_or(0);
+        println!("line {} of {} ({}%)", cur, total, pct);
+    }
+
+    fn confirm(&self, question: &str) -> bool {
+        println!("{}{} [y/N]{}\n", self.pal.warn, question, "\x1b[0m");
+        let mut s = String::new();
+        let _ = io::stdin().read_line(&mut s);
+        s.trim().eq_ignore_ascii_case("y")
+    }
+
+    // false if the file was refused and the current buffer left alone
+    fn load(&mut self, path: &str) -> bool {
+        let path_buf = PathBuf::from(path);
+        match load_file(&path_buf, &mut self.buf) {
+            Ok(_) => {
+                self.buf.path = Some(path_buf.clone());
+                *self.buf.git.borrow_mut() = None;
+                self.buf.marks.clear();
+                self.buf.view_top = 0;
+                self.buf.undo.clear();
+                self.buf.redo.clear();
+                self.buf.readonly = !file_writable(&path_buf);
+                if self.buf.readonly {
+                    println!("{}opened {} (read-only){}\x1b[0m", self.pal.warn, path, "");
+                } else if self.buf.encoding != "utf-8" {
+                    println!(
+                        "{}opened {} as {} (saves keep it; set encoding utf-8 to convert){}\x1b[0m",
+                        self.pal.warn, path, self.buf.encoding, ""
+                    );
+                } else {
+                    println!("{}opened {}{}\x1b[0m", self.pal.ok, path, "");
+                }
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                // binary, unreadable, a directory...: keep the current buffer as it was rather
+                // than an empty "(new)" one that a stray `w` would clobber the file with
+                println!("{}open: {}: {}{}\x1b[0m", self.pal.err, path, e, "");
+                return false;
+            }
+            Err(e) => {
+                self.buf = Buffer::new();
+                self.buf.path = Some(path_buf.clone());
+                println!("{}(new) {} ({}){}\x1b[0m", self.pal.warn, path, e, "");
+            }
+        }
+        self.set_line(1);
+        if recover_is_newer(&path_buf) && self.confirm("recover newer autosaved version?") {
+            self.recover();
+        }
+        true
+    }
+
+    fn reload(&mut self) {
+        let path = match &self.buf.path {
+            Some(p) => p.clone(),
+            None => {
+                println!("{}reload: buffer has no filename{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        if self.buf.dirty && !self.confirm("Discard unsaved changes and reload?") {
+            return;
+        }
+        let mut tmp = Buffer::new();
+        match load_file(&path, &mut tmp) {
+            Ok(_) => {
+                self.push_undo(1, self.buf.lines.len().max(tmp.lines.len()));
+                self.buf.lines = tmp.lines;
+                self.buf.crlf = tmp.crlf;
+                self.buf.final_newline = tmp.final_newline;
+                self.buf.encoding = tmp.encoding;
+                self.buf.dirty = false;
+                println!(
+                    "{}reloaded {} ({} lines){}\x1b[0m",
+                    self.pal.ok,
+                    path.display(),
+                    self.buf.lines.len(),
+                    ""
+                );
+            }
+            Err(e) => {
+                println!("{}reload: {}: {}{}\x1b[0m", self.pal.err, path.display(), e, "");
+            }
+        }
+    }
+
+    fn recover(&mut self) {
+        let path = match &self.buf.path {
+            Some(p) => p.clone(),
+            None => {
+                println!("{}recover: buffer has no filename{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let rec = recover_path(&path);
+        let mut tmp = Buffer::new();
+        match load_file(&rec, &mut tmp) {
+            Ok(_) => {
+                self.push_undo(1, self.buf.lines.len().max(tmp.lines.len()));
+                self.buf.lines = tmp.lines;
+                self.buf.dirty = true;
+                println!(
+                    "{}recovered {} line(s) from {}{}\x1b[0m",
+                    self.pal.ok,
+                    self.buf.lines.len(),
+                    rec.display(),
+                    ""
+                );
+            }
+            Err(e) => {
+                println!("{}recover: {}: {}{}\x1b[0m", self.pal.err, rec.display(), e, "");
+            }
+        }
+    }
+
+    fn print_line(&self, i: usize) {
+        self.print_line_in(i, "");
+    }
+
+    // `<args> <<TERM` -> (args, TERM); no `<<` means the usual "." terminator.
+    // None for a bare `<<`
+    fn split_heredoc(rest: &str) -> Option<(&str, &str)> {
+        match rest.split_once("<<") {
+            Some((args, term)) => {
+                let term = term.trim();
+                if term.is_empty() || term.contains(char::is_whitespace) {
+                    None
+                } else {
+                    Some((args.trim(), term))
+                }
+            }
+            None => Some((rest.trim(), ".")),
+        }
+    }
+
+    // typed lines for append/insert, up to a line that is exactly `term`. `\` + term
+    // stands for a literal `term` line (so `\.` gets you a lone dot); EOF or Ctrl-C
+    // ends it too, keeping what was typed before
+    fn read_text_block(&mut self, term: &str) -> Vec<String> {
+        println!("enter text; '{}' on a line ends", term);
+        let escaped = format!("\\{}", term);
+        let mut added = Vec::new();
+        let tty = atty::is(Stream::Stdin);
+        let prompt = format!("{}> \x1b[0m", self.pal.gutter);
+        let tab = " ".repeat(self.indent_width);
+        let mut typed = Vec::new();
+        let mut ended = false;
+        loop {
+            let mut s = String::new();
+            if let Some(l) = self.replay_text.pop_front() {
+                s = l;
+            } else if self.playing > 0 {
+                // a replayed block that came up short ends here, not at the keyboard
+                break;
+            } else if tty {
+                // the raw-mode reader, so autopair and the editing keys work here too
+                match self.lr.read_text(&prompt, &tab) {
+                    Ok(l) => s = l,
+                    Err(_) => break,
+                }
+            } else {
+                print!("> ");
+                let _ = io::stdout().flush();
+                match io::stdin().read_line(&mut s) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+            let s = s.trim_end_matches(&['\r', '\n'][..]).to_string();
+            typed.push(s.clone());
+            if s == term {
+                ended = true;
+                break;
+            }
+            added.push(if s == escaped { term.to_string() } else { s });
+        }
+        // a recorded append/insert keeps its text: the lines ride along in the same macro
+        // entry, after the command, so `play` can feed them back
+        if let (Some(reg), 0) = (self.recording, self.playing) {
+            if !ended {
+                typed.push(term.to_string());
+            }
+            if let Some(last) = self.macros.get_mut(&reg).and_then(|m| m.last_mut()) {
+                for l in typed {
+                    last.push('\n');
+                    last.push_str(&l);
+                }
+            }
+        }
+        added
+    }
+
+    // chdir, remembering where we were for `cd -`; `who` prefixes the messages
+    fn change_dir(&mut self, who: &str, target: &Path) -> bool {
+        let here = std::env::current_dir().ok();
+        if let Err(e) = std::env::set_current_dir(target) {
+            println!("{}{}: {}: {}{}\x1b[0m", self.pal.err, who, target.display(), e, "");
+            return false;
+        }
+        println!("{}{}: {}{}\x1b[0m", self.pal.ok, who, target.display(), "");
+        if here.is_some() {
+            self.prev_dir = here;
+        }
+        true
+    }
+
+    // shell-style directory stack: `pushd <dir>` cds and remembers where it came from,
+    // bare `pushd` swaps with the top, `popd` goes back, `dirs` lists (cwd first)
+    fn cmd_dirstack(&mut self, cmd: &str, rest: &str) {
+        let here = match std::env::current_dir() {
+            Ok(d) => d,
+            Err(e) => {
+                println!("{}{}: {}{}\x1b[0m", self.pal.err, cmd, e, "");
+                return;
+            }
+        };
+        match cmd {
+            "pushd" => {
+                let target = if rest.is_empty() {
+                    match self.dir_stack.pop() {
+                        Some(d) => d,
+                        None => {
+                            println!("{}pushd: no other directory{}\x1b[0m", self.pal.warn, "");
+                            return;
+                        }
+                    }
+                } else {
+                    self.expand_path(rest)
+                };
+                if self.change_dir("pushd", &target) {
+                    self.dir_stack.push(here);
+                } else if rest.is_empty() {
+                    self.dir_stack.push(target);
+                }
+            }
+            "popd" => match self.dir_stack.pop() {
+                Some(d) => {
+                    // a directory that's gone since stays dropped, like bash
+                    self.change_dir("popd", &d);
+                }
+                None => println!("{}popd: directory stack is empty{}\x1b[0m", self.pal.warn, ""),
+            },
+            _ => {
+                let stack = std::iter::once(&here).chain(self.dir_stack.iter().rev());
+                for (i, d) in stack.enumerate() {
+                    println!("  {}{:>2}\x1b[0m {}", self.pal.gutter, i, d.display());
+                }
+            }
+        }
+    }
+
+    // `blame [n]`: who last touched line n (default: the current line) of the file on disk
+    fn cmd_blame(&self, arg: &str) {
+        let path = match &self.buf.path {
+            Some(p) => p.clone(),
+            None => {
+                println!("{}blame: buffer has no file{}\x1b[0m", self.pal.err, "");
+                return;
+            }
+        };
+        let n = if arg.is_empty() { Some(self.current_line()) } else { self.addr(arg) };
+        let n = match n {
+            Some(n) if n >= 1 => n,
+            _ => {
+                println!("{}usage: blame [n]{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        let name = path.file_name().unwrap_or_default();
+        let out = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["blame", "--porcelain", "-L", &format!("{},{}", n, n), "--"])
+        .arg(name)
+        .stdin(Stdio::null())
+        .output();
+        let out = match out {
+            Ok(o) => o,
+            Err(e) => {
+                println!("{}blame: cannot run git: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr);
+            let first = err.lines().next().unwrap_or("git blame failed");
+            println!("{}blame: {}{}\x1b[0m", self.pal.err, first, "");
+            return;
+        }
+        let b = match parse_blame(&String::from_utf8_lossy(&out.stdout)) {
+            Some(b) => b,
+            None => {
+                println!("{}blame: no output from git{}\x1b[0m", self.pal.err, "");
+                return;
+            }
+        };
+        if self.buf.dirty {
+            println!("{}(unsaved changes; this is line {} as saved){}\x1b[0m", self.pal.dim, n, "");
+        }
+        if b.commit.chars().all(|c| c == '0') {
+            println!("{}line {} is not committed yet{}\x1b[0m", self.pal.dim, n, "");
+            return;
+        }
+        let short: String = b.commit.chars().take(8).collect();
+        let when = format_time(UNIX_EPOCH + Duration::from_secs(b.time));
+        println!(
+            "{}{}\x1b[0m {} {} {}{}\x1b[0m",
+            self.pal.accent, short, b.author, b.mail, self.pal.dim, when
+        );
+        if !b.summary.is_empty() {
+            println!("  {}", b.summary);
+        }
+    }
+
+    // gitgutter mark for line `i`; asks git for HEAD's copy once per buffer, then only
+    // re-diffs when the lines have changed since the last call
+    fn git_mark(&self, i: usize) -> char {
+        if !self.gitgutter {
+            return ' ';
+        }
+        let path = match &self.buf.path {
+            Some(p) => p,
+            None => return ' ',
+        };
+        let mut cache = self.buf.git.borrow_mut();
+        let c = cache.get_or_insert_with(|| GitCache {
+            head: git_head_lines(path),
+            key: 0,
+            marks: Vec::new(),
+        });
+        let head = match &c.head {
+            Some(h) => h,
+            None => return ' ',
+        };
+        if self.git_stale.replace(false) {
+            let key = fxhash::hash64(self.buf.lines.join("\n").as_bytes());
+            if key != c.key || c.marks.len() != self.buf.lines.len() {
+                c.marks = gutter_marks(head, &self.buf.lines);
+                c.key = key;
+            }
+        }
+        c.marks.get(i - 1).copied().unwrap_or(' ')
+    }
+
+    // wrap hits of the last search in the accent color, then go back to `color`
+    fn hl_search(&self, text: &str, color: &str) -> String {
+        if !self.buf.highlight || self.nohl || self.last_search.is_empty() || !use_color() {
+            return text.to_string();
+        }
+        let pat = if self.last_regex {
+            self.last_search.clone()
+        } else {
+            regex::escape(&self.last_search)
+        };
+        let re = match RegexBuilder::new(&pat).case_insensitive(self.last_icase).build() {
+            Ok(re) => re,
+            Err(_) => return text.to_string(),
+        };
+        let mut out = String::new();
+        let mut last = 0;
+        for m in re.find_iter(text) {
+            if m.as_str().is_empty() {
+                continue;
+            }
+            out.push_str(&text[last..m.start()]);
+            out.push_str(self.pal.accent);
+            out.push_str(m.as_str());
+            out.push_str("\x1b[0m");
+            out.push_str(color);
+            last = m.end();
+        }
+        out.push_str(&text[last..]);
+        out
+    }
+
+    // `color` tints the text (not the gutter); "" prints it plain
+    fn print_line_in(&self, i: usize, color: &str) {
+        if i == 0 || i > self.buf.lines.len() {
+            return;
+        }
+        let line = escape_controls(&self.buf.lines[i - 1]);
+        let line = line.as_ref();
+        let reset = if color.is_empty() { "" } else { "\x1b[0m" };
+        let gw = if self.buf.number {
+            digits_for(self.buf.lines.len()) + 3
+        } else if self.gitgutter {
+            2
+        } else {
+            0
+        };
+        // with gitgutter the `|` separator (or a column of its own) shows how the line
+        // differs from HEAD
+        let sep = match self.git_mark(i) {
+            '+' => format!("{}+\x1b[0m{}", self.pal.ok, self.pal.gutter),
+            '~' => format!("{}~\x1b[0m{}", self.pal.warn, self.pal.gutter),
+            '-' => format!("{}-\x1b[0m{}", self.pal.err, self.pal.gutter),
+            _ if self.buf.number => "|".to_string(),
+            _ => " ".to_string(),
+        };
+        if self.buf.number {
+            let cur = self.current_line();
+            // the current line keeps its real number so you know where you are
+            let shown = if self.buf.relnum && i != cur { i.abs_diff(cur) } else { i };
+            print!(
+                "{}{:>width$} {} {}\x1b[0m",
+                self.pal.gutter,
+                shown,
+                sep,
+                "",
+                width = gw - 3
+            );
+        } else if self.gitgutter {
+            print!("{}{} \x1b[0m", self.pal.gutter, sep);
+        }
+        let tw = term_width();
+        let max = if tw > gw { tw - gw } else { tw };
+        if self.truncate_long {
+            if let Some(cut) = truncate_chars(line, max) {
+                let cut = self.hl_search(&cut, color);
+                println!("{}{}…{}", color, cut, reset);
+            } else {
+                println!("{}{}{}", color, self.hl_search(line, color), reset);
+            }
+        } else if self.wrap_long && line.chars().count() > max {
+            // soft wrap: continuation rows get a blank gutter so text stays aligned
+            let chars: Vec<char> = line.chars().collect();
+            for (row, chunk) in chars.chunks(max.max(1)).enumerate() {
+                if row > 0 && self.buf.number {
+                    print!(
+                        "{}{:>width$} | {}\x1b[0m",
+                        self.pal.gutter,
+                        "",
+                        "",
+                        width = gw - 3
+                    );
+                } else if row > 0 && gw > 0 {
+                    print!("{:width$}", "", width = gw);
+                }
+                let chunk: String = chunk.iter().collect();
+                println!("{}{}{}", color, self.hl_search(&chunk, color), reset);
+            }
+        } else {
+            println!("{}{}{}", color, self.hl_search(line, color), reset);
+        }
+    }
+
+    fn print_range(&self, lo: usize, hi: usize) {
+        if self.buf.lines.is_empty() {
+            println!("(empty)");
+            return;
+        }
+        let lo = lo.max(1);
+        let hi = hi.min(self.buf.lines.len());
+        self.git_stale.set(true);
+        for i in lo..=hi {
+            self.print_line(i);
+        }
+    }
+
+    // a screenful around `at` (the status bar and prompt keep a couple of rows)
+    fn view_rows(&self) -> usize {
+        term_height().saturating_sub(3).max(1)
+    }
+
+    // print the window starting at `top`, tinting line `hl` (0 = none)
+    fn show_view(&mut self, top: usize, hl: usize) {
+        if self.buf.lines.is_empty() {
+            println!("(empty)");
+            return;
+        }
+        let rows = self.view_rows();
+        let last_top = self.buf.lines.len().saturating_sub(rows - 1).max(1);
+        let top = top.clamp(1, last_top);
+        let end = (top + rows - 1).min(self.buf.lines.len());
+        self.git_stale.set(true);
+        for i in top..=end {
+            if i == hl {
+                self.print_line_in(i, self.pal.accent);
+            } else {
+                self.print_line(i);
+            }
+        }
+        self.buf.view_top = top;
+        self.set_line(if hl == 0 { top } else { hl });
+    }
+
+    // `follow`: print what gets appended to the file on disk until a key is pressed
+    fn cmd_follow(&self) {
+        let path = match &self.buf.path {
+            Some(p) => p.clone(),
+            None => {
+                println!("{}follow: buffer has no file{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let mut pos = match fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(e) => {
+                println!("{}follow: {}: {}{}\x1b[0m", self.pal.err, path.display(), e, "");
+                return;
+            }
+        };
+        println!("{}following {} — any key stops{}\x1b[0m", self.pal.dim, path.display(), "");
+        while poll_key(500).is_none() {
+            let len = match fs::metadata(&path) {
+                Ok(m) => m.len(),
+                Err(_) => continue,
+            };
+            if len < pos {
+                println!("{}(file truncated, starting over){}\x1b[0m", self.pal.warn, "");
+                pos = 0;
+            }
+            if len == pos {
+                continue;
+            }
+            if let Ok(mut f) = File::open(&path) {
+                let mut chunk = Vec::new();
+                if f.seek(SeekFrom::Start(pos)).is_ok() && f.read_to_end(&mut chunk).is_ok() {
+                    for part in String::from_utf8_lossy(&chunk).split_inclusive('\n') {
+                        match part.strip_suffix('\n') {
+                            Some(l) => println!("{}", escape_controls(l.trim_end_matches('\r'))),
+                            None => print!("{}", escape_controls(part)),
+                        }
+                    }
+                    let _ = io::stdout().flush();
+                    pos += chunk.len() as u64;
+                }
+            }
+        }
+        println!("{}(stopped; `reload` pulls the new lines into the buffer){}\x1b[0m",
+            self.pal.dim, "");
+    }
+
+    // `spell`: flag words the dictionary doesn't know; anything with digits or
+    // punctuation inside is taken for code and skipped
+    fn cmd_spell(&self) {
+        let text = match fs::read_to_string(&self.dict) {
+            Ok(t) => t,
+            Err(e) => {
+                println!(
+                    "{}spell: {}: {} (set dict <path>){}\x1b[0m",
+                    self.pal.err, self.dict, e, ""
+                );
+                return;
+            }
+        };
+        let words: std::collections::HashSet<String> = text.lines().map(lower).collect();
+        let mut flagged = 0;
+        for (i, line) in self.buf.lines.iter().enumerate() {
+            let mut bad = Vec::new();
+            for tok in line.split_whitespace() {
+                let w = tok.trim_matches(|c: char| !c.is_alphanumeric());
+                let w = w.strip_suffix("'s").unwrap_or(w);
+                if w.len() < 2 || !w.chars().all(|c| c.is_alphabetic() || c == '\'') {
+                    continue;
+                }
+                let lw = lower(w);
+                if !words.contains(&lw) && !words.contains(lw.trim_end_matches('s')) {
+                    bad.push(w);
+                }
+            }
+            if !bad.is_empty() {
+                println!("{}{:>5}:\x1b[0m {}", self.pal.gutter, i + 1, bad.join(", "));
+                flagged += bad.len();
+            }
+        }
+        if flagged == 0 {
+            println!("{}spell: nothing flagged{}\x1b[0m", self.pal.ok, "");
+        } else {
+            println!("{}spell: {} unknown word(s){}\x1b[0m", self.pal.warn, flagged, "");
+        }
+    }
+
+    // lo..=hi: the lines the running command is about to touch
+    fn push_undo(&mut self, lo: usize, hi: usize) {
+        self.git_stale.set(true);
+        self.buf.undo.push_coalesced(&self.buf.lines, lo, hi, &self.cur_cmd);
+        self.buf.redo.clear();
+    }
+
+    fn show_restored(&mut self, what: &str, lo: usize, hi: usize) {
+        println!("{} (lines {}-{})", what, lo, hi.max(lo));
+        self.print_range(lo.saturating_sub(2), hi.max(lo) + 2);
+        self.set_line(lo);
+    }
+
+    fn save(&mut self, path_opt: Option<&str>) {
+        if path_opt.is_none() && self.buf.readonly {
+            println!(
+                "{}buffer is read-only (set readonly off, or w <path>){}\x1b[0m",
+                self.pal.warn, ""
+            );
+            return;
+        }
+        let target = if let Some(p) = path_opt {
+            PathBuf::from(p)
+        } else if let Some(p) = &self.buf.path {
+            p.clone()
+        } else {
+            println!("{}save: no filename{}\x1b[0m", self.pal.warn, "");
+            return;
+        };
+
+        if self.trim_on_save && !self.buf.readonly {
+            let n = self.buf.trim_trailing();
+            if n > 0 {
+                println!("{}trimmed {} line(s){}\x1b[0m", self.pal.dim, n, "");
+            }
+        }
+        let backup = if self.buf.backup { Some(&self.backup) } else { None };
+        match atomic_save(&target, &self.buf, backup) {
+            Ok(atomic) => {
+                let _ = fs::remove_file(recover_path(&target));
+                if self.buf.path.as_ref() != Some(&target) {
+                    // HEAD's copy of the old name says nothing about the new one
+                    *self.buf.git.borrow_mut() = None;
+                }
+                self.buf.path = Some(target.clone());
+                self.buf.dirty = false;
+                println!("{}saved to {:?}{}\x1b[0m", self.pal.ok, target, "");
+                if !atomic {
+                    println!("{}{}{}\x1b[0m", self.pal.warn, NOT_ATOMIC, "");
+                }
+            }
+            Err(e) => {
+                println!("{}save: {}{}\x1b[0m", self.pal.err, e, "");
+            }
+        }
+    }
+
+    fn save_all(&mut self) {
+        let pal_ok = self.pal.ok;
+        let pal_err = self.pal.err;
+        let pal_warn = self.pal.warn;
+        let trim = self.trim_on_save;
+        for b in std::iter::once(&mut self.buf).chain(self.others.iter_mut()) {
+            if !b.dirty || b.readonly {
+                continue;
+            }
+            let path = match &b.path {
+                Some(p) => p.clone(),
+                None => continue,
+            };
+            if trim {
+                b.trim_trailing();
+            }
+            let backup = if b.backup { Some(&self.backup) } else { None };
+            match atomic_save(&path, b, backup) {
+                Ok(atomic) => {
+                    let _ = fs::remove_file(recover_path(&path));
+                    b.dirty = false;
+                    println!("{}saved to {:?}{}\x1b[0m", pal_ok, path, "");
+                    if !atomic {
+                        println!("{}{}{}\x1b[0m", pal_warn, NOT_ATOMIC, "");
+                    }
+                }
+                Err(e) => println!("{}save {}: {}{}\x1b[0m", pal_err, path.display(), e, ""),
+            }
+        }
+    }
+
+    // true if nothing is dirty, or the user agrees to drop the listed buffers
+    fn confirm_quit(&self) -> bool {
+        let dirty: Vec<&Buffer> = std::iter::once(&self.buf)
+            .chain(self.others.iter())
+            .filter(|b| b.dirty)
+            .collect();
+        if dirty.is_empty() {
+            return true;
+        }
+        println!("{}unsaved buffers:{}\x1b[0m", self.pal.warn, "");
+        for b in &dirty {
+            println!("  {} *", b.name());
+        }
+        self.confirm("Unsaved changes. Quit anyway?")
+    }
+
+    fn autosave_if_needed(&mut self) {
+        if self.autosave_sec == 0 {
+            return;
+        }
+        if self.buf.dirty && self.last_autosave.elapsed() >= Duration::from_secs(self.autosave_sec) {
+            if let Some(p) = &self.buf.path {
+                if let Ok(mut f) = File::create(recover_path(p)) {
+                    for l in &self.buf.lines {
+                        let _ = writeln!(f, "{}", l);
+                    }
+                }
+            }
+            self.last_autosave = Instant::now();
+        }
+    }
+
+    fn recover_list(&self) {
+        let files = recover_files();
+        if files.is_empty() {
+            println!("no recover files in {}", home_path().display());
+            return;
+        }
+        for (path, md) in &files {
+            let when = md.modified().map(format_time).unwrap_or_else(|_| "?".to_string());
+            println!(
+                "{:16}  {:8}  {}",
+                when,
+                md.len(),
+                path.file_name().unwrap_or_default().to_string_lossy()
+            );
+        }
+        println!("{}{} recover file(s){}\x1b[0m", self.pal.dim, files.len(), "");
+    }
+
+    fn recover_clean(&self) {
+        let files = recover_files();
+        if files.is_empty() {
+            println!("no recover files in {}", home_path().display());
+            return;
+        }
+        if !self.confirm(&format!("delete {} recover file(s)?", files.len())) {
+            return;
+        }
+        let mut removed = 0usize;
+        for (path, _) in &files {
+            match fs::remove_file(path) {
+                Ok(_) => removed += 1,
+                Err(e) => println!(
+                    "{}recover-clean: {}: {}{}\x1b[0m",
+                    self.pal.err,
+                    path.display(),
+                    e,
+                    ""
+                ),
+            }
+        }
+        println!("{}removed {} recover file(s){}\x1b[0m", self.pal.ok, removed, "");
+    }
+
+    fn list_buffers(&self) {
+        let mark = |b: &Buffer| if b.dirty { " *" } else { "" };
+        println!(
+            "\x1b[1m* 0 {}{} ({} lines)\x1b[0m",
+            self.buf.name(),
+            mark(&self.buf),
+            self.buf.lines.len()
+        );
+        for (i, b) in self.others.iter().enumerate() {
+            println!(
+                "  {} {}{} {}({} lines)\x1b[0m",
+                i + 1,
+                b.name(),
+                mark(b),
+                self.pal.dim,
+                b.lines.len()
+            );
+        }
+    }
+
+    fn bnext(&mut self) {
+        if self.others.is_empty() {
+            println!("(only one buffer)");
+            return;
+        }
+        let next = self.others.pop().unwrap();
+        self.others.insert(0, std::mem::replace(&mut self.buf, next));
+        println!("[bnext] {}", self.buf.name());
+    }
+
+    fn bprev(&mut self) {
+        if self.others.is_empty() {
+            println!("(only one buffer)");
+            return;
+        }
+        let last = self.others.pop().unwrap();
+        self.others.insert(0, std::mem::replace(&mut self.buf, last));
+        println!("[bprev] {}", self.buf.name());
+    }
+
+    // completion words that depend on editor state; refreshed before every prompt
+    fn sync_completion(&mut self) {
+        let ids: Vec<String> = (1..=self.others.len()).map(|n| n.to_string()).collect();
+        self.lr.set_arg_words(&["buffer", "b"], &ids);
+    }
+
+    fn switch_buffer(&mut self, arg: &str) {
+        let n = match arg.trim().parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                println!("{}usage: buffer <n> (see lsb){}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        if n == 0 {
+            println!("[buffer] {} (already current)", self.buf.name());
+            return;
+        }
+        if n > self.others.len() {
+            println!("{}buffer: no buffer {} (see lsb){}\x1b[0m", self.pal.err, n, "");
+            return;
+        }
+        std::mem::swap(&mut self.buf, &mut self.others[n - 1]);
+        println!("[buffer] {}", self.buf.name());
+    }
+
+    fn close_buffer(&mut self) {
+        if self.buf.dirty && !self.confirm("Buffer has unsaved changes. Close anyway?") {
+            return;
+        }
+        let closed = self.buf.name();
+        // undo/redo live on the buffer, so they go away with it
+        self.buf = self.others.pop().unwrap_or_else(Buffer::new);
+        println!("[close] {} -> {}", closed, self.buf.name());
+    }
+
+    fn set_theme(&mut self, name: &str) {
+        let t = theme_from_name(name).unwrap_or(Theme::Default);
+        self.theme = t;
+        self.pal = palette_for(t, self.light_bg);
+        // update line reader input color too
+        self.lr.set_input_color(self.pal.input);
+        println!("{}theme set{}\x1b[0m", self.pal.ok, "");
+    }
+
+    // bare `theme`: every theme with a sample in its own colors
+    fn list_themes(&self) {
+        let reset = if use_color() { "\x1b[0m" } else { "" };
+        for t in Theme::ALL {
+            let p = palette_for(t, self.light_bg);
+            let name = lower(&format!("{:?}", t));
+            let mark = if t == self.theme { "*" } else { " " };
+            println!(
+                "{} {}{:<10}{r} {}{r}{}The quick brown fox{r}  {}ok{r} {}warn{r} {}err{r} {}dim{r}",
+                mark,
+                p.title,
+                name,
+                gradient_prompt_text("trust>", &p),
+                p.input,
+                p.ok,
+                p.warn,
+                p.err,
+                p.dim,
+                r = reset
+            );
+        }
+    }
+
+    // every `set` key with its current value, in SET_KEYS order
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("autosave", format!("{}", self.autosave_sec)),
+            ("wrap", on_off(self.wrap_long).to_string()),
+            ("truncate", on_off(self.truncate_long).to_string()),
+            ("backup", on_off(self.buf.backup).to_string()),
+            ("backup_ext", self.backup.ext.clone()),
+            ("backup_dir", match &self.backup.dir {
+                Some(d) => d.display().to_string(),
+                None => "off".to_string(),
+            }),
+            ("backup_keep", self.backup.keep.to_string()),
+            ("number", on_off(self.buf.number).to_string()),
+            ("hlsearch", on_off(self.buf.highlight).to_string()),
+            ("gitgutter", on_off(self.gitgutter).to_string()),
+            ("numbers", match (self.buf.number, self.buf.relnum) {
+                (false, _) => "off".to_string(),
+                (true, false) => "absolute".to_string(),
+                (true, true) => "relative".to_string(),
+            }),
+            ("indent", format!("{}", self.indent_width)),
+            ("theme", lower(&format!("{:?}", self.theme))),
+            ("rustfmt_confirm", on_off(self.rustfmt_confirm).to_string()),
+            ("edition", self.edition.clone()),
+            ("pager", on_off(self.pager).to_string()),
+            ("readonly", on_off(self.buf.readonly).to_string()),
+            ("trim_on_save", on_off(self.trim_on_save).to_string()),
+            ("autopair", on_off(self.lr.autopair).to_string()),
+            ("background", (if self.light_bg { "light" } else { "dark" }).to_string()),
+            ("prompt", self.prompt_style.clone()),
+            ("prompt_format", if self.prompt_format.is_empty() {
+                "default".to_string()
+            } else {
+                self.prompt_format.clone()
+            }),
+            ("dict", self.dict.clone()),
+            ("encoding", self.buf.encoding.to_string()),
+        ]
+    }
+
+    fn show_settings(&self) {
+        for (k, v) in self.settings() {
+            println!("  {}{:<16}\x1b[0m {}", self.pal.help_cmd, k, v);
+        }
+    }
+
+    fn cmd_set(&mut self, args: &str) {
+        let mut p = args.splitn(2, ' ');
+        let key = lower(p.next().unwrap_or(""));
+        let raw = p.next().unwrap_or("");
+        let val = raw.trim();
+        if key.is_empty() {
+            self.show_settings();
+            return;
+        }
+        if val.is_empty() {
+            println!("{}usage: set <key> <value>{}\x1b[0m", self.pal.warn, "");
+            return;
+        }
+        match key.as_str() {
+            "autosave" => match val.parse::<u64>() {
+                Ok(n) => self.autosave_sec = n,
+                Err(_) => {
+                    println!(
+                        "{}set: autosave expects seconds (0 = off), got '{}'{}\x1b[0m",
+                        self.pal.err, val, ""
+                    );
+                    return;
+                }
+            },
+            "indent" => match val.parse::<usize>() {
+                Ok(n) if n > 0 => self.indent_width = n,
+                _ => {
+                    println!(
+                        "{}set: indent expects a positive number, got '{}'{}\x1b[0m",
+                        self.pal.err, val, ""
+                    );
+                    return;
+                }
+            },
+            "edition" => match val {
+                "2015" | "2018" | "2021" | "2024" => self.edition = val.to_string(),
+                _ => {
+                    println!(
+                        "{}set: edition must be 2015, 2018, 2021 or 2024{}\x1b[0m",
+                        self.pal.err, ""
+                    );
+                    return;
+                }
+            },
+            "theme" => {
+                if theme_from_name(val).is_none() {
+                    println!("{}set: unknown theme '{}'{}\x1b[0m", self.pal.err, val, "");
+                    return;
+                }
+                self.set_theme(val);
+                return;
+            }
+            "numbers" => match val {
+                "off" => self.buf.number = false,
+                "absolute" | "relative" => {
+                    self.buf.number = true;
+                    self.buf.relnum = val == "relative";
+                }
+                _ => {
+                    println!(
+                        "{}set: numbers must be absolute, relative or off{}\x1b[0m",
+                        self.pal.err, ""
+                    );
+                    return;
+                }
+            },
+            "prompt" => match val {
+                "plain" | "gradient" => self.prompt_style = val.to_string(),
+                _ => {
+                    println!("{}set: prompt must be plain or gradient{}\x1b[0m", self.pal.err, "");
+                    return;
+                }
+            },
+            "backup_ext" => {
+                if val.contains('/') {
+                    println!("{}set: backup_ext can't contain '/'{}\x1b[0m", self.pal.err, "");
+                    return;
+                }
+                self.backup.ext = val.to_string();
+            }
+            "backup_dir" => {
+                self.backup.dir = match val {
+                    "off" | "none" => None,
+                    _ => Some(self.expand_path(val)),
+                }
+            }
+            "backup_keep" => match val.parse::<usize>() {
+                Ok(n) => self.backup.keep = n,
+                Err(_) => {
+                    println!(
+                        "{}set: backup_keep expects a number, got '{}'{}\x1b[0m",
+                        self.pal.err, val, ""
+                    );
+                    return;
+                }
+            },
+            "encoding" => match lower(val).as_str() {
+                "utf-8" | "utf8" => self.buf.encoding = "utf-8",
+                "windows-1252" | "cp1252" | "latin1" | "latin-1" => {
+                    self.buf.encoding = "windows-1252"
+                }
+                _ => {
+                    println!(
+                        "{}set: encoding must be utf-8 or windows-1252{}\x1b[0m",
+                        self.pal.err, ""
+                    );
+                    return;
+                }
+            },
+            "dict" => self.dict = self.expand_path(val).to_string_lossy().to_string(),
+            "prompt_format" => {
+                if val == "default" {
+                    self.prompt_format.clear();
+                } else if expand_prompt_format(val, &self.buf, self.theme).is_none() {
+                    println!(
+                        "{}set: prompt_format knows %f %l %m %d %t and %%{}\x1b[0m",
+                        self.pal.err, ""
+                    );
+                    return;
+                } else {
+                    // keep trailing spaces: they separate the prompt from what's typed
+                    self.prompt_format = raw.trim_start().to_string();
+                }
+            }
+            "background" => match val {
+                "light" | "dark" => {
+                    self.light_bg = val == "light";
+                    self.pal = palette_for(self.theme, self.light_bg);
+                    self.lr.set_input_color(self.pal.input);
+                }
+                _ => {
+                    println!("{}set: background must be light or dark{}\x1b[0m", self.pal.err, "");
+                    return;
+                }
+            },
+            "wrap" | "truncate" | "backup" | "number" | "rustfmt_confirm" | "pager"
+            | "trim_on_save" | "readonly" | "hlsearch" | "gitgutter" | "autopair" => {
+                let b = match parse_on_off(val) {
+                    Some(b) => b,
+                    None => {
+                        println!(
+                            "{}set: {} expects on/off, got '{}'{}\x1b[0m",
+                            self.pal.err, key, val, ""
+                        );
+                        return;
+                    }
+                };
+                match key.as_str() {
+                    "wrap" => self.wrap_long = b,
+                    "truncate" => self.truncate_long = b,
+                    "backup" => self.buf.backup = b,
+                    "rustfmt_confirm" => self.rustfmt_confirm = b,
+                    "pager" => self.pager = b,
+                    "trim_on_save" => self.trim_on_save = b,
+                    "autopair" => self.lr.autopair = b,
+                    "readonly" => self.buf.readonly = b,
+                    "hlsearch" => self.buf.highlight = b,
+                    "gitgutter" => {
+                        // toggling is also how you pick up commits made since the last look
+                        self.gitgutter = b;
+                        for buf in std::iter::once(&self.buf).chain(self.others.iter()) {
+                            *buf.git.borrow_mut() = None;
+                        }
+                        let path = self.buf.path.clone();
+                        if b && path.is_some_and(|p| git_head_lines(&p).is_none()) {
+                            println!(
+                                "{}gitgutter: {} is not in a git repo{}\x1b[0m",
+                                self.pal.warn,
+                                self.buf.name(),
+                                ""
+                            );
+                        }
+                    }
+                    _ => self.buf.number = b,
+                }
+            }
+            _ => {
+                println!("{}set: unknown key '{}'{}\x1b[0m", self.pal.err, key, "");
+                return;
+            }
+        }
+        println!("{}{} = {}{}\x1b[0m", self.pal.ok, key, val, "");
+    }
+
+    fn reflow(&mut self, args: &str) {
+        let mut p = args.split_whitespace();
+        let range = p.next().unwrap_or("");
+        let width = match p.next() {
+            Some(w) => match w.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    println!("{}reflow: bad width '{}'{}\x1b[0m", self.pal.err, w, "");
+                    return;
+                }
+            },
+            None => term_width(),
+        };
+        if self.buf.lines.is_empty() {
+            println!("(empty)");
+            return;
+        }
+        let (lo, hi) = match self.range(range) {
+            Some(r) => r,
+            None => {
+                println!("{}bad range{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let new_lines = reflow_lines(&self.buf.lines[lo - 1..hi], width);
+        let n = new_lines.len();
+        self.push_undo(lo, hi);
+        self.buf.lines.splice(lo - 1..hi, new_lines);
+        self.shift_marks(lo, hi - lo + 1, n);
+        self.buf.dirty = true;
+        self.set_line(lo + n - 1);
+        println!(
+            "{}reflowed {} line(s) into {} at width {}{}\x1b[0m",
+            self.pal.ok,
+            hi - lo + 1,
+            n,
+            width,
+            ""
+        );
+    }
+
+    // `pipe <range> <cmd>`: filter the range through `sh -c cmd`, replacing it with stdout
+    fn cmd_pipe(&mut self, args: &str) {
+        let (range, shell) = match args.split_once(' ') {
+            Some((r, c)) if !c.trim().is_empty() => (r, c.trim()),
+            _ => {
+                println!("{}usage: pipe <range> <cmd>{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        if self.buf.lines.is_empty() {
+            println!("(empty)");
+            return;
+        }
+        let (lo, hi) = match self.range(range) {
+            Some(r) => r,
+            None => {
+                println!("{}bad range{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let mut input = self.buf.lines[lo - 1..hi].join("\n");
+        input.push('\n');
+        let child = Command::new("sh")
+        .arg("-c")
+        .arg(shell)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => {
+                println!("{}pipe: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        // feed stdin from a thread so a chatty program can't deadlock us on a full pipe
+        let feeder = child.stdin.take().map(|mut w| {
+            std::thread::spawn(move || {
+                let _ = w.write_all(input.as_bytes());
+            })
+        });
+        let out = child.wait_with_output();
+        if let Some(t) = feeder {
+            let _ = t.join();
+        }
+        let out = match out {
+            Ok(o) => o,
+            Err(e) => {
+                println!("{}pipe: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        if !out.status.success() {
+            println!(
+                "{}pipe: '{}' failed ({}), buffer unchanged{}\x1b[0m",
+                self.pal.err, shell, out.status, ""
+            );
+            eprint!("{}", String::from_utf8_lossy(&out.stderr));
+            return;
         }
+        let text = String::from_utf8_lossy(&out.stdout);
+        let new_lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        let n = new_lines.len();
+        self.push_undo(lo, hi);
+        self.buf.lines.splice(lo - 1..hi, new_lines);
+        self.shift_marks(lo, hi - lo + 1, n);
+        self.buf.dirty = true;
+        self.set_line((lo + n).saturating_sub(1).max(1));
+        println!("{}pipe: {} line(s) -> {}{}\x1b[0m", self.pal.ok, hi - lo + 1, n, "");
     }
 
-    fn prompt(&self) -> String {
-        gradient_prompt_text(self.buf.dirty, &self.pal)
-    }
-
-    fn status(&self) {
-        let lang = detect_lang_from_path(self.buf.path.as_ref());
+    // `revert <range>`: put those lines back the way the file on disk has them (by number)
+    fn cmd_revert(&mut self, args: &str) {
+        let path = match &self.buf.path {
+            Some(p) => p.clone(),
+            None => {
+                println!("{}revert: buffer has no file{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        if args.is_empty() {
+            println!("{}usage: revert <range>{}\x1b[0m", self.pal.warn, "");
+            return;
+        }
+        let (lo, hi) = match self.range(args) {
+            Some(r) => r,
+            None => {
+                println!("{}bad range{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let mut disk = Buffer::new();
+        if let Err(e) = load_file(&path, &mut disk) {
+            println!("{}revert: {}: {}{}\x1b[0m", self.pal.err, path.display(), e, "");
+            return;
+        }
+        // the file may be shorter than the buffer; past its end there's nothing to restore
+        let from = (lo - 1).min(disk.lines.len());
+        let new_lines = disk.lines[from..hi.min(disk.lines.len())].to_vec();
+        if new_lines[..] == self.buf.lines[lo - 1..hi] {
+            println!("revert: lines {}-{} already match the file", lo, hi);
+            return;
+        }
+        let n = new_lines.len();
+        self.push_undo(lo, hi);
+        self.buf.lines.splice(lo - 1..hi, new_lines);
+        self.shift_marks(lo, hi - lo + 1, n);
+        self.buf.dirty = true;
+        self.set_line((lo + n).saturating_sub(1).max(1));
         println!(
-            "{}[{}] lines={} chars={} lang={} theme={:?} wrap:{}{}\x1b[0m",
-            self.pal.dim,
-            self.buf.name(),
-                 self.buf.lines.len(),
-                 self.buf.char_count(),
-                 lang,
-                 self.theme,
-                 if self.wrap_long { "on" } else { "off" },
-                     ""
+            "{}revert: lines {}-{} restored from {} ({} line(s)){}\x1b[0m",
+            self.pal.ok, lo, hi, path.display(), n, ""
         );
     }
 
-    fn load(&mut self, path: &str) {
-        let path_buf = PathBuf::from(path);
-        match load_file(&path_buf, &mut self.buf) {
-            Ok(_) => {
-                self.buf.path = Some(path_buf);
-                println!("{}opened {}{}\x1b[0m", self.pal.ok, path, "");
+    // `open!`: load `path` into a new buffer, keeping the current one in the list
+    fn open_keep(&mut self, path: &str) {
+        // stash like `new` does, unless there's nothing worth keeping
+        let scratch = self.buf.path.is_none() && self.buf.lines.is_empty() && !self.buf.dirty;
+        if !scratch {
+            self.others.push(std::mem::replace(&mut self.buf, Buffer::new()));
+        }
+        if !self.load(path) && !scratch {
+            if let Some(prev) = self.others.pop() {
+                self.buf = prev;
             }
+        }
+    }
+
+    // `session save|restore <name>`. a session file is plain trust commands: `open!` per
+    // buffer (active last, so it ends up current) and then `set` for each setting
+    fn cmd_session(&mut self, args: &str) {
+        let mut p = args.split_whitespace();
+        let (sub, name) = (p.next().unwrap_or(""), p.next().unwrap_or(""));
+        if sub.is_empty() {
+            let mut names: Vec<String> = fs::read_dir(session_dir())
+                .map(|rd| {
+                    rd.flatten().map(|e| e.file_name().to_string_lossy().to_string()).collect()
+                })
+            .unwrap_or_default();
+            names.sort();
+            if names.is_empty() {
+                println!("no sessions in {}", session_dir().display());
+            }
+            for n in names {
+                println!("  {}", n);
+            }
+            return;
+        }
+        if name.is_empty() || name.contains('/') || !matches!(sub, "save" | "restore") {
+            println!("{}usage: session [save|restore <name>]{}\x1b[0m", self.pal.warn, "");
+            return;
+        }
+        let file = session_dir().join(name);
+        if sub == "save" {
+            let mut out = String::from("# trust session; `session restore` replays these\n");
+            for b in self.others.iter().chain(std::iter::once(&self.buf)) {
+                if let Some(path) = &b.path {
+                    let abs = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                    out.push_str(&format!("open! {}\n", abs.display()));
+                }
+            }
+            for (k, v) in self.settings() {
+                // these live on the current buffer, so restoring them would only ever
+                // hit whichever buffer happened to be opened last
+                let per_buffer = ["backup", "number", "numbers", "hlsearch", "readonly"];
+                if !per_buffer.contains(&k) && k != "encoding" {
+                    out.push_str(&format!("set {} {}\n", k, v));
+                }
+            }
+            let res = fs::create_dir_all(session_dir()).and_then(|_| fs::write(&file, out));
+            match res {
+                Ok(_) => {
+                    println!("{}session saved to {}{}\x1b[0m", self.pal.ok, file.display(), "")
+                }
+                Err(e) => println!("{}session: {}{}\x1b[0m", self.pal.err, e, ""),
+            }
+            return;
+        }
+        let text = match fs::read_to_string(&file) {
+            Ok(t) => t,
             Err(e) => {
-                self.buf = Buffer::new();
-                self.buf.path = Some(path_buf);
-                println!("{}(new) {} ({}){}\x1b[0m", self.pal.warn, path, e, "");
+                println!("{}session: {}: {}{}\x1b[0m", self.pal.err, file.display(), e, "");
+                return;
+            }
+        };
+        // straight to open_keep/cmd_set rather than handle: aliases and `record` stay out of
+        // it, and values keep their spacing (a prompt_format may end in a space)
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // only what `session save` writes; anything else in there is not ours to run
+            if let Some(path) = line.strip_prefix("open! ") {
+                self.open_keep(path);
+            } else if let Some(set) = line.strip_prefix("set ") {
+                self.cmd_set(set);
+            } else {
+                println!("{}session: skipping '{}'{}\x1b[0m", self.pal.warn, line, "");
             }
         }
     }
 
-    fn print_line(&self, i: usize) {
-        if i == 0 || i > self.buf.lines.len() {
-            return;
+    // `record <reg>` … `stop`, then `play <reg> [count]` feeds the lines back through handle.
+    // false = a replayed line quit the editor
+    fn cmd_play(&mut self, args: &str) -> bool {
+        let mut p = args.split_whitespace();
+        let reg = match p.next() {
+            Some(r) if r.chars().count() == 1 => r.chars().next().unwrap_or('a'),
+            Some(_) => {
+                println!("{}usage: play <reg> [count]{}\x1b[0m", self.pal.warn, "");
+                return true;
+            }
+            None => {
+                if self.macros.is_empty() {
+                    println!("no macros recorded");
+                }
+                let mut regs: Vec<_> = self.macros.iter().collect();
+                regs.sort();
+                for (r, cmds) in regs {
+                    let shown: Vec<String> =
+                        cmds.iter().map(|c| c.replace('\n', " ⏎ ")).collect();
+                    println!("  {}{}\x1b[0m  {}", self.pal.help_cmd, r, shown.join(" | "));
+                }
+                return true;
+            }
+        };
+        let count = match p.next().map(|c| c.parse::<usize>()) {
+            None => 1,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => {
+                println!("{}play: count must be a positive number{}\x1b[0m", self.pal.warn, "");
+                return true;
+            }
+        };
+        let cmds = match self.macros.get(&reg) {
+            Some(c) if !c.is_empty() => c.clone(),
+            _ => {
+                println!("{}play: register '{}' is empty{}\x1b[0m", self.pal.warn, reg, "");
+                return true;
+            }
+        };
+        // a macro may play another, but not itself forever
+        if self.playing >= 8 {
+            println!("{}play: macros nested too deep{}\x1b[0m", self.pal.err, "");
+            return true;
         }
-        let line = &self.buf.lines[i - 1];
-        let gw = if self.buf.number {
-            digits_for(self.buf.lines.len()) + 3
-        } else {
-            0
+        self.playing += 1;
+        let mut keep_going = true;
+        'outer: for _ in 0..count {
+            for c in &cmds {
+                // an entry may carry the text its append/insert read, one line per `\n`
+                let mut lines = c.split('\n');
+                let cmd = lines.next().unwrap_or("");
+                self.replay_text = lines.map(str::to_string).collect();
+                let ok = self.handle(cmd);
+                self.replay_text.clear();
+                if !ok {
+                    keep_going = false;
+                    break 'outer;
+                }
+            }
+        }
+        self.playing -= 1;
+        keep_going
+    }
+
+    // `readcmd <n> <cmd>`: insert the stdout of `sh -c cmd` before line n (ed's `r !cmd`)
+    fn cmd_readcmd(&mut self, args: &str) {
+        let (at, shell) = match args.split_once(' ') {
+            Some((a, c)) if !c.trim().is_empty() => (a, c.trim()),
+            _ => {
+                println!("{}usage: readcmd <n> <cmd>{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
         };
-        if self.buf.number {
-            print!(
-                "{}{:>width$} | {}\x1b[0m",
-                self.pal.gutter,
-                i,
-                "",
-                width = gw - 3
+        let n = match self.addr(at) {
+            Some(n) => n.max(1),
+            None => {
+                println!("{}bad line{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        println!("{}[sh -c {:?}]{}\x1b[0m", self.pal.dim, shell, "");
+        let out = match Command::new("sh").arg("-c").arg(shell).stdin(Stdio::null()).output() {
+            Ok(o) => o,
+            Err(e) => {
+                println!("{}readcmd: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        eprint!("{}", String::from_utf8_lossy(&out.stderr));
+        if !out.status.success() {
+            println!(
+                "{}readcmd: '{}' failed ({}), nothing inserted{}\x1b[0m",
+                self.pal.err, shell, out.status, ""
             );
+            return;
         }
-        if self.truncate_long {
-            let tw = term_width();
-            let max = if tw > gw { tw - gw } else { tw };
-            if line.len() > max {
-                println!("{}…", &line[..max.saturating_sub(1)]);
-            } else {
-                println!("{}", line);
+        let text = String::from_utf8_lossy(&out.stdout);
+        let added: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        if added.is_empty() {
+            println!("readcmd: no output");
+            return;
+        }
+        let idx = (n - 1).min(self.buf.lines.len());
+        let count = added.len();
+        self.push_undo(idx + 1, idx + 1);
+        for (i, l) in added.into_iter().enumerate() {
+            self.buf.lines.insert(idx + i, l);
+        }
+        self.shift_marks(idx + 1, 0, count);
+        self.buf.dirty = true;
+        self.set_line(idx + count);
+        println!("{}readcmd: inserted {} line(s) at {}{}\x1b[0m", self.pal.ok, count, idx + 1, "");
+    }
+
+    // read-only hex view of the range as it would be saved (UTF-8, one newline per line)
+    fn cmd_hex(&self, args: &str) {
+        if self.buf.lines.is_empty() {
+            println!("(empty)");
+            return;
+        }
+        let (lo, hi) = match self.range(args) {
+            Some(r) => r,
+            None => {
+                println!("{}usage: hex [range]{}\x1b[0m", self.pal.warn, "");
+                return;
             }
-        } else {
-            println!("{}", line);
+        };
+        let start: usize = self.buf.lines[..lo - 1].iter().map(|l| l.len() + 1).sum();
+        let mut bytes = Vec::new();
+        for l in &self.buf.lines[lo - 1..hi] {
+            bytes.extend_from_slice(l.as_bytes());
+            bytes.push(b'\n');
         }
+        // 16 per row needs ~78 columns: offset, 16 hex cells, ascii
+        let per_row = if term_width() >= 78 { 16 } else { 8 };
+        self.page_text(&hex_dump(&bytes, start, per_row));
     }
 
-    fn print_range(&self, lo: usize, hi: usize) {
+    // expand [-a] <range> / unexpand <range>
+    fn retab(&mut self, args: &str, expand: bool) {
+        let (all, range) = match args.strip_prefix("-a") {
+            Some(r) if expand => (true, r.trim()),
+            _ => (false, args),
+        };
+        let name = if expand { "expand" } else { "unexpand" };
+        if range.is_empty() {
+            let usage = if expand { "expand [-a] <range>" } else { "unexpand <range>" };
+            println!("{}usage: {}{}\x1b[0m", self.pal.warn, usage, "");
+            return;
+        }
         if self.buf.lines.is_empty() {
             println!("(empty)");
             return;
         }
-        let lo = lo.max(1);
-        let hi = hi.min(self.buf.lines.len());
-        for i in lo..=hi {
-            self.print_line(i);
+        let (lo, hi) = match self.range(range) {
+            Some(r) => r,
+            None => {
+                println!("{}bad range{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let w = self.indent_width;
+        let new_lines: Vec<String> = self.buf.lines[lo - 1..hi]
+            .iter()
+            .map(|l| if expand { expand_tabs(l, w, all) } else { unexpand_tabs(l, w) })
+            .collect();
+        let old = &self.buf.lines[lo - 1..hi];
+        let changed = new_lines.iter().zip(old).filter(|(a, b)| a != b).count();
+        if changed == 0 {
+            println!("{}: nothing to change", name);
+            return;
         }
+        self.push_undo(lo, hi);
+        self.buf.lines.splice(lo - 1..hi, new_lines);
+        self.buf.dirty = true;
+        println!("{}{}: {} line(s) changed{}\x1b[0m", self.pal.ok, name, changed, "");
     }
 
-    fn push_undo(&mut self) {
-        self.undo.push(&self.buf);
-        self.redo.clear();
+    // "10-40 foo" -> (Some((10, 40)), "foo"); anything else is all text
+    fn split_range_arg<'a>(&self, rest: &'a str) -> (Option<(usize, usize)>, &'a str) {
+        let mut p = rest.splitn(2, ' ');
+        let first = p.next().unwrap_or("");
+        let text = p.next().unwrap_or("").trim();
+        let looks_like_range = first.chars().any(|c| c.is_ascii_digit() || c == '.' || c == '$')
+            && first.chars().all(|c| c.is_ascii_digit() || "-+,.$".contains(c));
+        if looks_like_range && !text.is_empty() {
+            if let Some(r) = self.range(first) {
+                return (Some(r), text);
+            }
+        }
+        (None, rest)
     }
 
-    fn save(&mut self, path_opt: Option<&str>) {
-        let target = if let Some(p) = path_opt {
-            PathBuf::from(p)
-        } else if let Some(p) = &self.buf.path {
-            p.clone()
+    fn print_match_summary(&self, hits: usize, lines: usize) {
+        if hits == 0 {
+            println!("no matches");
         } else {
-            println!("{}save: no filename{}\x1b[0m", self.pal.warn, "");
-            return;
-        };
+            println!(
+                "{}{} match{} on {} line{}{}\x1b[0m",
+                self.pal.ok,
+                hits,
+                if hits == 1 { "" } else { "es" },
+                lines,
+                if lines == 1 { "" } else { "s" },
+                ""
+            );
+        }
+    }
 
-        match atomic_save(&target, &self.buf, self.buf.backup) {
-            Ok(_) => {
-                self.buf.path = Some(target.clone());
-                self.buf.dirty = false;
-                println!("{}saved to {:?}{}\x1b[0m", self.pal.ok, target, "");
+    // returns the number of matches (not lines); `quiet` skips listing them
+    // ctx = (before, after) lines of context around each hit, grep -B/-A style
+    fn search_plain(
+        &mut self,
+        q: &str,
+        icase: bool,
+        lo: usize,
+        hi: usize,
+        quiet: bool,
+        ctx: (usize, usize),
+    ) -> usize {
+        let mut hits = 0usize;
+        let mut hit_at = Vec::new();
+        let q_norm = if icase { lower(q) } else { q.to_string() };
+        let lo = lo.max(1);
+        let hi = hi.min(self.buf.lines.len());
+        for (i, line) in self.buf.lines.iter().enumerate() {
+            if i + 1 < lo || i + 1 > hi {
+                continue;
+            }
+            let cmp = if icase { lower(line) } else { line.to_string() };
+            let n = cmp.matches(&q_norm).count();
+            if n > 0 {
+                hits += n;
+                hit_at.push(i + 1);
+            }
+        }
+        if quiet {
+            return hits;
+        }
+        let (before, after) = ctx;
+        // next line not yet printed, so overlapping windows merge instead of repeating
+        let mut shown_to = 0;
+        for (k, &n) in hit_at.iter().enumerate() {
+            let from = n.saturating_sub(before).max(1).max(shown_to);
+            if (before > 0 || after > 0) && k > 0 && from > shown_to {
+                println!("{}--{}\x1b[0m", self.pal.dim, "");
+            }
+            for c in from..n {
+                println!("{}{:>8}- {}{}\x1b[0m", self.pal.dim, c, self.buf.lines[c - 1], "");
+            }
+            println!("match at {}: {}", n, self.buf.lines[n - 1]);
+            // stop short of the next hit; it prints itself
+            let next = hit_at.get(k + 1).copied().unwrap_or(usize::MAX);
+            let to = (n + after).min(self.buf.lines.len()).min(next - 1);
+            for c in n + 1..=to {
+                println!("{}{:>8}- {}{}\x1b[0m", self.pal.dim, c, self.buf.lines[c - 1], "");
             }
+            shown_to = to.max(n) + 1;
+        }
+        self.print_match_summary(hits, hit_at.len());
+        hits
+    }
+
+    fn search_regex(&mut self, pat: &str, icase: bool, lo: usize, hi: usize) {
+        let re = match RegexBuilder::new(pat).case_insensitive(icase).build() {
+            Ok(re) => re,
             Err(e) => {
-                println!("{}save: {}{}\x1b[0m", self.pal.err, e, "");
+                println!("{}regex: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        self.last_search = pat.to_string();
+        self.nohl = false;
+        self.last_icase = icase;
+        self.last_regex = true;
+        let mut hits = 0usize;
+        let mut hit_lines = 0usize;
+        let lo = lo.max(1);
+        let hi = hi.min(self.buf.lines.len());
+        for (i, line) in self.buf.lines.iter().enumerate() {
+            if i + 1 < lo || i + 1 > hi || !re.is_match(line) {
+                continue;
+            }
+            let mut shown = String::new();
+            let mut last = 0;
+            for m in re.find_iter(line) {
+                shown.push_str(&line[last..m.start()]);
+                shown.push_str(self.pal.accent);
+                shown.push_str(m.as_str());
+                shown.push_str("\x1b[0m");
+                last = m.end();
+                hits += 1;
             }
+            shown.push_str(&line[last..]);
+            println!("match at {}: {}", i + 1, shown);
+            hit_lines += 1;
         }
+        self.print_match_summary(hits, hit_lines);
     }
 
-    fn autosave_if_needed(&mut self) {
-        if self.autosave_sec == 0 {
+    // `global /re/ <cmd>` (globalv: lines NOT matching); cmd is print (default) or delete
+    fn cmd_global(&mut self, args: &str, invert: bool) {
+        let name = if invert { "globalv" } else { "global" };
+        let delim = match args.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() => c,
+            _ => {
+                println!("{}usage: {} /pattern/ [print|delete]{}\x1b[0m", self.pal.warn, name, "");
+                return;
+            }
+        };
+        let body = &args[delim.len_utf8()..];
+        let mut end = None;
+        let mut esc = false;
+        for (i, c) in body.char_indices() {
+            if c == delim && !esc {
+                end = Some(i);
+                break;
+            }
+            esc = c == '\\' && !esc;
+        }
+        let (pat, cmd) = match end {
+            Some(i) => (&body[..i], body[i + delim.len_utf8()..].trim()),
+            None => (body, ""),
+        };
+        let re = match RegexBuilder::new(pat).build() {
+            Ok(re) => re,
+            Err(e) => {
+                println!("{}regex: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        // collect first so deleting doesn't shift the lines we still have to visit
+        let hits: Vec<usize> = (0..self.buf.lines.len())
+        .filter(|&i| re.is_match(&self.buf.lines[i]) != invert)
+        .collect();
+        if hits.is_empty() {
+            println!("no matches");
             return;
         }
-        if self.buf.dirty && self.last_autosave.elapsed() >= Duration::from_secs(self.autosave_sec) {
-            if let Some(p) = &self.buf.path {
-                let mut rec = home_path();
-                let hash = fxhash::hash64(p.to_string_lossy().as_bytes());
-                rec.push(format!(".trust-recover-{:x}", hash));
-                if let Ok(mut f) = File::create(&rec) {
-                    for l in &self.buf.lines {
-                        let _ = writeln!(f, "{}", l);
-                    }
+        match lower(cmd).as_str() {
+            "" | "p" | "print" => {
+                for &i in &hits {
+                    self.print_line(i + 1);
                 }
+                self.set_line(hits[hits.len() - 1] + 1);
             }
-            self.last_autosave = Instant::now();
+            "d" | "delete" => {
+                if self.buf.readonly {
+                    println!("{}buffer is read-only{}\x1b[0m", self.pal.warn, "");
+                    return;
+                }
+                let (first, last) = (hits[0] + 1, hits[hits.len() - 1] + 1);
+                self.push_undo(first, last);
+                for &i in hits.iter().rev() {
+                    self.buf.lines.remove(i);
+                    self.shift_marks(i + 1, 1, 0);
+                }
+                self.buf.dirty = true;
+                self.set_line(first);
+                println!("{}{}: deleted {} line(s){}\x1b[0m", self.pal.ok, name, hits.len(), "");
+            }
+            other => println!(
+                "{}{}: '{}' not supported here (print, delete){}\x1b[0m",
+                self.pal.warn, name, other, ""
+            ),
         }
     }
 
-    fn list_buffers(&self) {
-        println!("\x1b[1m* 0 {}\x1b[0m", self.buf.name());
-        for (i, b) in self.others.iter().enumerate() {
-            println!("  {} {}", i + 1, b.name());
+    // screenful at a time: space/enter for more, q to stop
+    fn page_text(&self, text: &str) {
+        let page = term_height().saturating_sub(1).max(1);
+        let lines: Vec<&str> = text.lines().collect();
+        let mut shown = 0;
+        while shown < lines.len() {
+            let end = (shown + page).min(lines.len());
+            for l in &lines[shown..end] {
+                println!("{}", l);
+            }
+            shown = end;
+            if shown >= lines.len() || !atty::is(Stream::Stdin) {
+                continue;
+            }
+            print!(
+                "{}-- more ({}/{}) space: next, q: stop --{}\x1b[0m",
+                self.pal.dim,
+                shown,
+                lines.len(),
+                ""
+            );
+            let _ = io::stdout().flush();
+            let key = read_key();
+            print!("\r\x1b[2K");
+            if matches!(key, Some(b'q') | Some(b'Q') | Some(3) | None) {
+                println!(
+                    "{}({} more lines skipped){}\x1b[0m",
+                    self.pal.dim,
+                    lines.len() - shown,
+                    ""
+                );
+                break;
+            }
         }
     }
 
-    fn bnext(&mut self) {
-        if self.others.is_empty() {
-            println!("(only one buffer)");
-            return;
+    fn page_output(&self, out: &std::process::Output) {
+        let mut text = String::from_utf8_lossy(&out.stdout).to_string();
+        text.push_str(&String::from_utf8_lossy(&out.stderr));
+        self.page_text(&text);
+    }
+
+    // run with stdin/stdout passed through (or paged) and hand back stderr for diagnostics
+    fn run_collect(&self, cmd: &mut Command) -> io::Result<(std::process::ExitStatus, String)> {
+        cmd.stdin(Stdio::inherit());
+        if self.pager {
+            let o = cmd.output()?;
+            self.page_output(&o);
+            return Ok((o.status, String::from_utf8_lossy(&o.stderr).to_string()));
         }
-        self.others.insert(0, self.buf.clone());
-        self.buf = self.others.pop().unwrap();
-        println!("[bnext] {}", self.buf.name());
+        if use_color() && atty::is(Stream::Stderr) {
+            // stderr is a pipe now, which would cost cargo/rustc their colors and cargo its
+            // progress bar; ask for both anyway and strip the escapes before parsing
+            cmd.env("CARGO_TERM_COLOR", "always")
+            .env("CARGO_TERM_PROGRESS_WHEN", "always")
+            .env("CARGO_TERM_PROGRESS_WIDTH", term_width().to_string());
+            if cmd.get_program() == "rustc" {
+                cmd.arg("--color=always");
+            }
+        }
+        let mut child = cmd.stdout(Stdio::inherit()).stderr(Stdio::piped()).spawn()?;
+        let mut raw = Vec::new();
+        if let Some(mut err) = child.stderr.take() {
+            // pass bytes straight through so `\r` progress updates redraw in place
+            let mut chunk = [0u8; 4096];
+            loop {
+                let n = match err.read(&mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let mut e = io::stderr();
+                let _ = e.write_all(&chunk[..n]);
+                let _ = e.flush();
+                raw.extend_from_slice(&chunk[..n]);
+            }
+        }
+        let text = String::from_utf8_lossy(&raw).replace('\r', "\n");
+        let collected = regex::Regex::new("\x1b\\[[0-9;?]*[A-Za-z]")
+        .map(|re| re.replace_all(&text, "").to_string())
+        .unwrap_or(text);
+        Ok((child.wait()?, collected))
     }
 
-    fn bprev(&mut self) {
-        if self.others.is_empty() {
-            println!("(only one buffer)");
-            return;
+    fn collect_diags(&mut self, stderr: &str, scratch_src: Option<&Path>) {
+        let mut diags = parse_diagnostics(stderr);
+        if let Some(src) = scratch_src {
+            for d in &mut diags {
+                d.scratch = Path::new(&d.file) == src;
+            }
+        }
+        self.diags = diags;
+        self.diag_idx = 0;
+        if !self.diags.is_empty() {
+            println!(
+                "{}{} diagnostic(s) — 'cerr' steps through them{}\x1b[0m",
+                self.pal.warn,
+                self.diags.len(),
+                ""
+            );
         }
-        let last = self.others.pop().unwrap();
-        self.others.insert(0, self.buf.clone());
-        self.buf = last;
-        println!("[bprev] {}", self.buf.name());
     }
 
-    fn set_theme(&mut self, name: &str) {
-        let t = match lower(name).as_str() {
-            "dark" => Theme::Dark,
-            "neon" => Theme::Neon,
-            "matrix" => Theme::Matrix,
-            "paper" => Theme::Paper,
-            _ => Theme::Default,
-        };
-        self.theme = t;
-        self.pal = palette_for(t);
-        // update line reader input color too
-        self.lr.set_input_color(self.pal.input);
-        println!("{}theme set{}\x1b[0m", self.pal.ok, "");
+    fn diag_in_buffer(&self, d: &Diagnostic) -> bool {
+        if d.scratch {
+            return true;
+        }
+        match (&self.buf.path, fs::canonicalize(&d.file)) {
+            (Some(p), Ok(df)) => fs::canonicalize(p).map(|bp| bp == df).unwrap_or(false),
+            _ => false,
+        }
     }
 
-    fn search_plain(&mut self, q: &str, icase: bool) {
-        let mut hits = 0usize;
-        let q_norm = if icase { lower(q) } else { q.to_string() };
-        for (i, line) in self.buf.lines.iter().enumerate() {
-            let cmp = if icase { lower(line) } else { line.to_string() };
-            if cmp.contains(&q_norm) {
-                println!("match at {}: {}", i + 1, line);
-                hits += 1;
+    fn cmd_cerr(&mut self, args: &str) {
+        if self.diags.is_empty() {
+            println!("no diagnostics (run cargo check or rs-run first)");
+            return;
+        }
+        if args == "list" {
+            for (i, d) in self.diags.iter().enumerate() {
+                println!("  {:>3} {}:{}:{} {}", i + 1, d.file, d.line, d.col, d.msg);
+            }
+            return;
+        }
+        if !args.is_empty() {
+            match args.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= self.diags.len() => self.diag_idx = n - 1,
+                _ => {
+                    println!("{}usage: cerr [n|list]{}\x1b[0m", self.pal.warn, "");
+                    return;
+                }
             }
         }
-        if hits == 0 {
-            println!("no matches");
+        let d = self.diags[self.diag_idx].clone();
+        println!(
+            "{}[{}/{}] {}:{}:{}{}\x1b[0m {}",
+            self.pal.accent,
+            self.diag_idx + 1,
+            self.diags.len(),
+            d.file,
+            d.line,
+            d.col,
+            "",
+            d.msg
+        );
+        if self.diag_in_buffer(&d) {
+            self.print_line(d.line);
+            self.set_line(d.line);
+        } else {
+            println!("{}(not in this buffer — open {}){}\x1b[0m", self.pal.dim, d.file, "");
         }
+        self.diag_idx = (self.diag_idx + 1) % self.diags.len();
     }
 
-    fn cargo_cmd(&self, args: &[&str]) {
+    fn cargo_cmd(&mut self, args: &[&str]) {
         println!("{}[cargo {:?}]{}\x1b[0m", self.pal.dim, args, "");
         let mut cmd = Command::new("cargo");
         for a in args {
             cmd.arg(a);
         }
-        let status = cmd
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status();
-        match status {
-            Ok(s) => println!("{}cargo exited with {}{}\x1b[0m", self.pal.dim, s, ""),
+        match self.run_collect(&mut cmd) {
+            Ok((st, stderr)) => {
+                println!("{}cargo exited with {}{}\x1b[0m", self.pal.dim, st, "");
+                self.collect_diags(&stderr, None);
+            }
             Err(e) => println!("{}cargo error: {}{}\x1b[0m", self.pal.err, e, ""),
         }
     }
 
-    fn rustfmt_current(&mut self, range: Option<(usize, usize)>) {
+    // `diff`: what saving would change (disk vs buffer); `diff <path>`: that file vs the
+    // buffer; `diff <a> <b>`: two files, buffer untouched
+    fn cmd_diff(&self, args: &str) {
+        let paths: Vec<PathBuf> = args.split_whitespace().map(|a| self.expand_path(a)).collect();
+        let (old_path, new_path) = match paths.as_slice() {
+            [] => match &self.buf.path {
+                Some(p) => (p.clone(), None),
+                None => {
+                    println!("{}diff: buffer has no file (diff <path>){}\x1b[0m",
+                        self.pal.warn, "");
+                    return;
+                }
+            },
+            [a] => (a.clone(), None),
+            [a, b] => (a.clone(), Some(b.clone())),
+            _ => {
+                println!("{}usage: diff [path [path]]{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let read = |p: &Path| -> Option<Buffer> {
+            let mut b = Buffer::new();
+            match load_file(p, &mut b) {
+                Ok(_) => Some(b),
+                Err(e) => {
+                    println!("{}diff: {}: {}{}\x1b[0m", self.pal.err, p.display(), e, "");
+                    None
+                }
+            }
+        };
+        let old = match read(&old_path) {
+            Some(b) => b,
+            None => return,
+        };
+        let (new_lines, new_label) = match &new_path {
+            Some(p) => match read(p) {
+                Some(b) => (b.lines, p.display().to_string()),
+                None => return,
+            },
+            None => (self.buf.lines.clone(), format!("{} (buffer)", self.buf.name())),
+        };
+        let ops = diff_lines(&old.lines, &new_lines);
+        if ops.iter().all(|o| o.0 == DiffTag::Same) {
+            println!(
+                "{}no differences: {} / {}{}\x1b[0m",
+                self.pal.dim, old_path.display(), new_label, ""
+            );
+            return;
+        }
+        println!("{}--- {}{}\x1b[0m", self.pal.err, old_path.display(), "");
+        println!("{}+++ {}{}\x1b[0m", self.pal.ok, new_label, "");
+        self.print_diff(&ops, 0);
+    }
+
+    // unified-style hunks; `offset` shifts line numbers when diffing a slice of the buffer
+    fn print_diff(&self, ops: &[(DiffTag, &str)], offset: usize) {
+        const CTX: usize = 3;
+        let changed: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != DiffTag::Same).collect();
+        let mut k = 0;
+        while k < changed.len() {
+            // grow the hunk while the next change is within 2*CTX of the previous one
+            let start = changed[k].saturating_sub(CTX);
+            let mut last = changed[k];
+            while k + 1 < changed.len() && changed[k + 1] <= last + 2 * CTX + 1 {
+                k += 1;
+                last = changed[k];
+            }
+            let end = (last + CTX + 1).min(ops.len());
+            let count =
+                |r: &[(DiffTag, &str)], skip: DiffTag| r.iter().filter(|o| o.0 != skip).count();
+            let old_start = 1 + offset + count(&ops[..start], DiffTag::Add);
+            let new_start = 1 + offset + count(&ops[..start], DiffTag::Del);
+            let old_len = count(&ops[start..end], DiffTag::Add);
+            let new_len = count(&ops[start..end], DiffTag::Del);
+            println!(
+                "{}@@ -{},{} +{},{} @@{}\x1b[0m",
+                self.pal.accent, old_start, old_len, new_start, new_len, ""
+            );
+            for (tag, line) in &ops[start..end] {
+                match tag {
+                    DiffTag::Same => println!(" {}", line),
+                    DiffTag::Del => println!("{}-{}\x1b[0m", self.pal.err, line),
+                    DiffTag::Add => println!("{}+{}\x1b[0m", self.pal.ok, line),
+                }
+            }
+            k += 1;
+        }
+    }
+
+    fn rustfmt_current(&mut self, range: Option<(usize, usize)>, preview: bool) {
         let tmpdir = std::env::temp_dir();
         let tmpfile = tmpdir.join("trust-rustfmt.rs");
         {
@@ -1000,7 +4918,18 @@ impl Editor {
                 }
             }
         }
-        let out = Command::new("rustfmt").arg(&tmpfile).output();
+        let mut cmd = Command::new("rustfmt");
+        cmd.arg("--edition").arg(&self.edition);
+        // look next to the file (like cargo fmt would), or from the cwd for unnamed buffers
+        let start = match self.buf.path.as_ref().and_then(|p| fs::canonicalize(p).ok()) {
+            Some(p) => p.parent().map(|d| d.to_path_buf()),
+            None => std::env::current_dir().ok(),
+        };
+        if let Some(cfg) = start.and_then(|d| find_up(&d, &["rustfmt.toml", ".rustfmt.toml"])) {
+            println!("{}[rustfmt] using {}{}\x1b[0m", self.pal.dim, cfg.display(), "");
+            cmd.arg("--config-path").arg(cfg);
+        }
+        let out = cmd.arg(&tmpfile).output();
         match out {
             Ok(o) if o.status.success() => {
                 let mut s = String::new();
@@ -1008,7 +4937,20 @@ impl Editor {
                     let _ = f.read_to_string(&mut s);
                 }
                 let new_lines: Vec<String> = s.lines().map(|l| l.to_string()).collect();
-                self.push_undo();
+                let (ulo, uhi) = range.unwrap_or((1, self.buf.lines.len()));
+                let old = &self.buf.lines[ulo.max(1) - 1..uhi.min(self.buf.lines.len())];
+                if old == new_lines.as_slice() {
+                    println!("{}rustfmt: already formatted{}\x1b[0m", self.pal.ok, "");
+                    return;
+                }
+                if preview || self.rustfmt_confirm {
+                    self.print_diff(&diff_lines(old, &new_lines), ulo.max(1) - 1);
+                    if !self.confirm("apply?") {
+                        println!("{}rustfmt: not applied{}\x1b[0m", self.pal.dim, "");
+                        return;
+                    }
+                }
+                self.push_undo(ulo, uhi);
                 if let Some((lo, hi)) = range {
                     let lo = lo.max(1);
                     let hi = hi.min(self.buf.lines.len());
@@ -1034,83 +4976,310 @@ impl Editor {
         }
     }
 
-    fn insert_snip(&mut self, kind: &str) {
-        self.push_undo();
+    // json-check / json-fmt: parse the whole buffer; only json-fmt rewrites it
+    fn json_current(&mut self, format: bool) {
+        let text = self.buf.lines.join("\n");
+        let value: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(v) => v,
+            Err(e) => {
+                let offset: usize = self.buf.lines[..e.line().saturating_sub(1)]
+                    .iter()
+                    .map(|l| l.len() + 1)
+                    .sum::<usize>()
+                    + e.column().saturating_sub(1);
+                println!("{}json: {} (byte {}){}\x1b[0m", self.pal.err, e, offset, "");
+                if e.line() >= 1 {
+                    self.set_line(e.line());
+                }
+                return;
+            }
+        };
+        if !format {
+            println!("{}json: valid{}\x1b[0m", self.pal.ok, "");
+            return;
+        }
+        let pretty = match serde_json::to_string_pretty(&value) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("{}json: {}{}\x1b[0m", self.pal.err, e, "");
+                return;
+            }
+        };
+        let new_lines: Vec<String> = pretty.lines().map(|l| l.to_string()).collect();
+        if new_lines == self.buf.lines {
+            println!("{}json: already formatted{}\x1b[0m", self.pal.ok, "");
+            return;
+        }
+        let (old, n) = (self.buf.lines.len(), new_lines.len());
+        self.push_undo(1, old);
+        self.buf.lines = new_lines;
+        self.shift_marks(1, old, n);
+        self.buf.dirty = true;
+        println!("{}json: formatted ({} -> {} lines){}\x1b[0m", self.pal.ok, old, n, "");
+    }
+
+    // `rs-snip <kind> [line]`: insert before `line`, or after the current line by default
+    fn insert_snip(&mut self, args: &str) {
+        let (kind, at) = match args.rsplit_once(' ') {
+            Some((k, a)) if a.starts_with(|c: char| "0123456789.$+-'".contains(c)) => {
+                match self.addr(a) {
+                    Some(n) => (k.trim(), n.max(1)),
+                    None => {
+                        println!("{}bad line{}\x1b[0m", self.pal.warn, "");
+                        return;
+                    }
+                }
+            }
+            _ => (args, self.current_line() + 1),
+        };
+        let mut snip: Vec<String> = Vec::new();
         match kind {
             "main" => {
-                self.buf.lines.push("fn main() {".to_string());
-                self.buf
-                .lines
-                .push("    println!(\"hello from trust 🦀\");".to_string());
-                self.buf.lines.push("}".to_string());
+                snip.push("fn main() {".to_string());
+                snip.push("    println!(\"hello from trust 🦀\");".to_string());
+                snip.push("}".to_string());
             }
             "mod" => {
-                self.buf.lines.push("pub mod my_mod {".to_string());
-                self.buf.lines.push("    pub fn hi() {".to_string());
-                self.buf
-                .lines
-                .push("        println!(\"hi from module\");".to_string());
-                self.buf.lines.push("    }".to_string());
-                self.buf.lines.push("}".to_string());
+                snip.push("pub mod my_mod {".to_string());
+                snip.push("    pub fn hi() {".to_string());
+                snip.push("        println!(\"hi from module\");".to_string());
+                snip.push("    }".to_string());
+                snip.push("}".to_string());
             }
             x if x.starts_with("struct ") => {
                 let name = x.trim_start_matches("struct ").trim();
-                self.buf.lines.push(format!("pub struct {} {{", name));
-                self.buf.lines.push("    pub id: u32,".to_string());
-                self.buf.lines.push("}".to_string());
-                self.buf.lines.push(format!("impl {} {{", name));
-                self.buf
-                .lines
-                .push("    pub fn new(id: u32) -> Self {".to_string());
-                self.buf
-                .lines
-                .push("        Self { id }".to_string());
-                self.buf.lines.push("    }".to_string());
-                self.buf.lines.push("}".to_string());
+                snip.push(format!("pub struct {} {{", name));
+                snip.push("    pub id: u32,".to_string());
+                snip.push("}".to_string());
+                snip.push(format!("impl {} {{", name));
+                snip.push("    pub fn new(id: u32) -> Self {".to_string());
+                snip.push("        Self { id }".to_string());
+                snip.push("    }".to_string());
+                snip.push("}".to_string());
+            }
+            "test" => {
+                snip.push("#[cfg(test)]".to_string());
+                snip.push("mod tests {".to_string());
+                snip.push("    use super::*;".to_string());
+                snip.push(String::new());
+                snip.push("    #[test]".to_string());
+                snip.push("    fn it_works() {".to_string());
+                snip.push("        assert_eq!(2 + 2, 4);".to_string());
+                snip.push("    }".to_string());
+                snip.push("}".to_string());
+            }
+            x if x.starts_with("enum ") => {
+                let name = x.trim_start_matches("enum ").trim();
+                snip.push("#[derive(Debug, Clone, Copy, PartialEq, Eq)]".to_string());
+                snip.push(format!("pub enum {} {{", name));
+                snip.push("    First,".to_string());
+                snip.push("    Second,".to_string());
+                snip.push("}".to_string());
+            }
+            x if x.starts_with("trait ") => {
+                let name = x.trim_start_matches("trait ").trim();
+                snip.push(format!("pub trait {} {{", name));
+                snip.push("    fn name(&self) -> String;".to_string());
+                snip.push("}".to_string());
+            }
+            x if x == "derive" || x.starts_with("derive ") => {
+                let name = x.trim_start_matches("derive").trim();
+                let name = if name.is_empty() { "Data" } else { name };
+                snip.push("#[derive(Debug, Clone)]".to_string());
+                snip.push(format!("pub struct {} {{", name));
+                snip.push("    pub name: String,".to_string());
+                snip.push("}".to_string());
             }
             _ => {
                 println!(
-                    "{}rs-snip: unknown snippet (try: main, mod, struct Foo){}\x1b[0m",
-                         self.pal.warn, ""
+                    "{}rs-snip: unknown snippet (try: {}){}\x1b[0m",
+                         self.pal.warn, SNIP_KINDS, ""
                 );
                 return;
             }
         }
+        let idx = (at - 1).min(self.buf.lines.len());
+        let count = snip.len();
+        self.push_undo(idx + 1, idx + 1);
+        for (i, l) in snip.into_iter().enumerate() {
+            self.buf.lines.insert(idx + i, l);
+        }
+        self.shift_marks(idx + 1, 0, count);
         self.buf.dirty = true;
-        println!("{}snippet inserted{}\x1b[0m", self.pal.ok, "");
+        self.set_line(idx + count);
+        println!("{}snippet inserted at line {}{}\x1b[0m", self.pal.ok, idx + 1, "");
     }
 
     fn expand_path(&self, s: &str) -> PathBuf {
         if s == "~" {
             return home_path();
         }
-        if s.starts_with("~/") {
+        if let Some(rest) = s.strip_prefix("~/") {
             let mut p = home_path();
-            p.push(&s[2..]);
+            p.push(rest);
             return p;
         }
         PathBuf::from(s)
     }
 
+    // mkdir [-p] / rm [-r] / mv / cp, so small file chores don't need `!`
+    fn cmd_fileop(&mut self, op: &str, args: &str) {
+        let mut flag = false;
+        let mut paths = Vec::new();
+        for a in args.split_whitespace() {
+            match (op, a) {
+                ("mkdir", "-p") | ("rm", "-r") => flag = true,
+                _ => paths.push(self.expand_path(a)),
+            }
+        }
+        let want = if op == "mv" || op == "cp" { 2 } else { 1 };
+        if paths.len() != want {
+            let usage = match op {
+                "mkdir" => "mkdir [-p] <dir>",
+                "rm" => "rm [-r] <path>",
+                "mv" => "mv <src> <dst>",
+                _ => "cp <src> <dst>",
+            };
+            println!("{}usage: {}{}\x1b[0m", self.pal.warn, usage, "");
+            return;
+        }
+        let src = paths[0].clone();
+        let src = src.as_path();
+        // open buffers backed by src (or by something under it), matched by canonical path so
+        // `./foo` and `foo` are the same file; worked out before rm/mv make it unresolvable
+        let canon_src = fs::canonicalize(src).ok();
+        let affected: Vec<(usize, PathBuf)> = match &canon_src {
+            Some(c) if op == "rm" || op == "mv" => self.buffers_under(c),
+            _ => Vec::new(),
+        };
+        let res = match op {
+            "mkdir" if flag => fs::create_dir_all(src),
+            "mkdir" => fs::create_dir(src),
+            "rm" => {
+                let is_dir = fs::symlink_metadata(src).map(|m| m.is_dir()).unwrap_or(false);
+                if is_dir && !flag {
+                    println!("{}rm: {} is a directory (use rm -r){}\x1b[0m",
+                        self.pal.warn, src.display(), "");
+                    return;
+                }
+                let what = if is_dir { "directory" } else { "file" };
+                let open = if affected.is_empty() {
+                    String::new()
+                } else {
+                    let ids: Vec<String> = affected.iter().map(|(n, _)| n.to_string()).collect();
+                    format!(" (open in buffer {})", ids.join(", "))
+                };
+                if !self.confirm(&format!("Delete {} {}{}?", what, src.display(), open)) {
+                    println!("{}rm: cancelled{}\x1b[0m", self.pal.dim, "");
+                    return;
+                }
+                if is_dir { fs::remove_dir_all(src) } else { fs::remove_file(src) }
+            }
+            _ => {
+                // like the shell: a directory destination means "into it"
+                let mut dst = paths[1].clone();
+                if dst.is_dir() {
+                    if let Some(name) = src.file_name() {
+                        dst.push(name);
+                    }
+                }
+                let res = if op == "mv" {
+                    fs::rename(src, &dst)
+                } else {
+                    fs::copy(src, &dst).map(|_| ())
+                };
+                if res.is_ok() && op == "mv" {
+                    for (n, rel) in &affected {
+                        let to = if rel.as_os_str().is_empty() {
+                            dst.clone()
+                        } else {
+                            dst.join(rel)
+                        };
+                        println!(
+                            "{}mv: buffer {} now edits {}{}\x1b[0m",
+                            self.pal.dim, n, to.display(), ""
+                        );
+                        self.buffer_mut(*n).path = Some(to);
+                    }
+                }
+                paths[1] = dst;
+                res
+            }
+        };
+        if res.is_ok() && op == "rm" {
+            // the text only lives in the buffer now; say so, and make quitting ask
+            for (n, _) in &affected {
+                let b = self.buffer_mut(*n);
+                b.dirty = true;
+                let name = b.name();
+                println!(
+                    "{}rm: buffer {} still holds {}; `w` would write it back{}\x1b[0m",
+                    self.pal.warn, n, name, ""
+                );
+            }
+        }
+        match res {
+            Err(e) => println!("{}{}: {}: {}{}\x1b[0m", self.pal.err, op, src.display(), e, ""),
+            Ok(()) if want == 2 => println!(
+                "{}{}: {} -> {}{}\x1b[0m",
+                self.pal.ok, op, src.display(), paths[1].display(), ""
+            ),
+            Ok(()) => println!("{}{}: {}{}\x1b[0m", self.pal.ok, op, src.display(), ""),
+        }
+    }
+
+    // buffers (numbered as in lsb) whose file is `canon` or lies under it, with the path
+    // relative to `canon` ("" for the file itself)
+    fn buffers_under(&self, canon: &Path) -> Vec<(usize, PathBuf)> {
+        let all = std::iter::once(&self.buf).chain(self.others.iter());
+        all.enumerate()
+        .filter_map(|(n, b)| {
+            let p = fs::canonicalize(b.path.as_ref()?).ok()?;
+            let rel = p.strip_prefix(canon).ok()?.to_path_buf();
+            Some((n, rel))
+        })
+        .collect()
+    }
+
+    // buffer `n` as numbered by lsb (0 is the current one)
+    fn buffer_mut(&mut self, n: usize) -> &mut Buffer {
+        if n == 0 {
+            &mut self.buf
+        } else {
+            &mut self.others[n - 1]
+        }
+    }
+
     fn cmd_ls(&self, args: &str) {
         let mut all = false;
         let mut longfmt = false;
+        let mut human = false;
+        let mut by_time = false;
+        let mut by_size = false;
+        let mut reverse = false;
         let mut target = ".".to_string();
 
         for tok in args.split_whitespace() {
-            match tok {
-                "-a" => all = true,
-                "-l" => longfmt = true,
-                "-la" | "-al" => {
-                    all = true;
-                    longfmt = true;
-                }
-                other => {
-                    target = other.to_string();
+            match tok.strip_prefix('-').filter(|f| !f.is_empty()) {
+                Some(flags) => {
+                    for f in flags.chars() {
+                        match f {
+                            'a' => all = true,
+                            'l' => longfmt = true,
+                            'h' => human = true,
+                            't' => by_time = true,
+                            'S' => by_size = true,
+                            'r' => reverse = true,
+                            _ => {
+                                println!("{}ls: unknown flag -{}{}\x1b[0m", self.pal.err, f, "");
+                                return;
+                            }
+                        }
+                    }
                 }
+                None => target = tok.to_string(),
             }
         }
-
         // tiny safeguard like C++: don't ls /etc/shadow if non-root, huihfguwioeghew lol
         if target == "/etc/shadow" && unsafe { libc::geteuid() } != 0 {
             println!("ls: permission denied");
@@ -1132,23 +5301,33 @@ impl Editor {
                     entries.push(e);
                 }
             }
-            entries.sort_by_key(|e| e.file_name());
-            for e in entries {
-                let name = e.file_name().to_string_lossy().to_string();
-                if !all && name.starts_with('.') {
-                    continue;
-                }
+            let mut entries: Vec<(String, Option<Metadata>)> = entries
+            .into_iter()
+            .map(|e| (e.file_name().to_string_lossy().to_string(), e.metadata().ok()))
+            .filter(|(name, _)| all || !name.starts_with('.'))
+            .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            // like ls: -t newest first, -S biggest first, ties stay in name order
+            if by_size {
+                let size = |m: &Option<Metadata>| m.as_ref().map(|m| m.len()).unwrap_or(0);
+                entries.sort_by_key(|e| std::cmp::Reverse(size(&e.1)));
+            } else if by_time {
+                let mtime = |m: &Option<Metadata>| m.as_ref().and_then(|m| m.modified().ok());
+                entries.sort_by_key(|e| std::cmp::Reverse(mtime(&e.1)));
+            }
+            if reverse {
+                entries.reverse();
+            }
+            for (name, emd) in entries {
                 let mut shown = name.clone();
-                let emd = e.metadata().ok();
                 let is_dir = emd.as_ref().map(|m| m.is_dir()).unwrap_or(false);
                 if is_dir {
                     shown.push('/');
                 }
+                let shown = self.ls_paint(emd.as_ref(), shown);
                 if longfmt {
                     if let Some(m) = emd {
-                        let perms = perm_string(&m);
-                        let size = m.len();
-                        println!("{:10} {:8}  {}", perms, size, shown);
+                        println!("{}", ls_long_line(&m, &shown, &path.join(&name), human));
                     } else {
                         println!("??????????        ?  {}", shown);
                     }
@@ -1158,49 +5337,279 @@ impl Editor {
             }
         } else {
             if longfmt {
-                let perms = perm_string(&md);
-                let size = md.len();
+                let md = fs::symlink_metadata(&path).unwrap_or(md);
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                let name = self.ls_paint(Some(&md), name);
+                println!("{}", ls_long_line(&md, &name, &path, human));
+            } else {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                let md = fs::symlink_metadata(&path).unwrap_or(md);
+                println!("{}", self.ls_paint(Some(&md), name));
+            }
+        }
+    }
+
+    // `ffind <glob>`: file names under the cwd matching the glob
+    fn cmd_ffind(&self, pat: &str) {
+        if pat.is_empty() {
+            println!("{}usage: ffind <glob>  (e.g. ffind *.rs){}\x1b[0m", self.pal.warn, "");
+            return;
+        }
+        let (files, truncated) = walk_files(Path::new("."));
+        let mut hits = 0;
+        for f in &files {
+            let name = f.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+            if glob_match(pat, &name) {
+                println!("{}", f.strip_prefix(".").unwrap_or(f).display());
+                hits += 1;
+            }
+        }
+        if hits == 0 {
+            println!("no files match '{}'", pat);
+        }
+        if truncated {
+            println!(
+                "{}ffind: stopped after {} files; cd deeper to narrow it{}\x1b[0m",
+                self.pal.warn, WALK_MAX_FILES, ""
+            );
+        }
+    }
+
+    // `grep [-i] <text> [glob]`: plain-text search over file contents below the cwd
+    fn cmd_grep(&self, args: &str) {
+        let (icase, args) = match args.strip_prefix("-i ") {
+            Some(r) => (true, r.trim_start()),
+            None => (false, args),
+        };
+        let mut parts = args.split_whitespace();
+        let pat = match parts.next() {
+            Some(p) => p,
+            None => {
+                println!("{}usage: grep [-i] <text> [glob]{}\x1b[0m", self.pal.warn, "");
+                return;
+            }
+        };
+        let glob = parts.next();
+        let needle = if icase { lower(pat) } else { pat.to_string() };
+        let (files, truncated) = walk_files(Path::new("."));
+        let mut hits = 0;
+        'files: for f in &files {
+            if let Some(g) = glob {
+                let name = f.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+                if !glob_match(g, &name) {
+                    continue;
+                }
+            }
+            let bytes = match fs::read(f) {
+                Ok(b) => b,
+                Err(_) => continue,
+            };
+            if is_binary(&bytes) {
+                continue;
+            }
+            let text = String::from_utf8_lossy(&bytes);
+            let shown = f.strip_prefix(".").unwrap_or(f).display();
+            for (i, line) in text.lines().enumerate() {
+                let hay = if icase { lower(line) } else { line.to_string() };
+                if !hay.contains(&needle) {
+                    continue;
+                }
+                if hits == GREP_MAX_HITS {
+                    println!(
+                        "{}grep: stopped after {} matches; narrow it with a glob{}\x1b[0m",
+                        self.pal.warn, GREP_MAX_HITS, ""
+                    );
+                    break 'files;
+                }
                 println!(
-                    "{:10} {:8}  {}",
-                    perms,
-                    size,
-                    path.file_name().unwrap().to_string_lossy()
+                    "{}{}:{}:\x1b[0m {}",
+                    self.pal.gutter, shown, i + 1, escape_controls(line.trim_end())
                 );
-            } else {
-                println!("{}", path.file_name().unwrap().to_string_lossy());
+                hits += 1;
+            }
+        }
+        if hits == 0 {
+            println!("no matches for '{}'", pat);
+        }
+        if truncated {
+            println!(
+                "{}grep: only searched the first {} files{}\x1b[0m",
+                self.pal.warn, WALK_MAX_FILES, ""
+            );
+        }
+    }
+
+    // `fuzzy <query>`: rank project files by fuzzy_score, then offer to open one by number
+    fn cmd_fuzzy(&mut self, query: &str) {
+        if query.is_empty() {
+            println!("{}usage: fuzzy <query>{}\x1b[0m", self.pal.warn, "");
+            return;
+        }
+        let (files, _) = walk_files(Path::new("."));
+        let mut ranked: Vec<(i32, String)> = files
+            .iter()
+            .map(|f| f.strip_prefix(".").unwrap_or(f).to_string_lossy().to_string())
+            .filter_map(|f| fuzzy_score(&f, query).map(|s| (s, f)))
+            .collect();
+        if ranked.is_empty() {
+            println!("no files match '{}'", query);
+            return;
+        }
+        ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        ranked.truncate(20);
+        for (i, (_, f)) in ranked.iter().enumerate() {
+            println!("  {}{:>2}\x1b[0m {}", self.pal.gutter, i + 1, f);
+        }
+        print!("open # (enter to skip): ");
+        let _ = io::stdout().flush();
+        let mut s = String::new();
+        let _ = io::stdin().read_line(&mut s);
+        match s.trim().parse::<usize>() {
+            // same checks as `open`, but called directly so aliases and `record` stay out
+            Ok(n) if n >= 1 && n <= ranked.len() && self.buf.dirty => {
+                println!(
+                    "{}unsaved changes, save first (or open! {} to keep this buffer){}\x1b[0m",
+                    self.pal.warn,
+                    ranked[n - 1].1,
+                    ""
+                );
+            }
+            Ok(n) if n >= 1 && n <= ranked.len() => {
+                self.load(&ranked[n - 1].1);
             }
+            _ if s.trim().is_empty() => {}
+            _ => println!("{}fuzzy: no entry '{}'{}\x1b[0m", self.pal.warn, s.trim(), ""),
         }
     }
 
+    // ls --color: dirs accent, symlinks warn, executables ok, everything else plain
+    fn ls_paint(&self, meta: Option<&Metadata>, name: String) -> String {
+        let m = match meta {
+            Some(m) if use_color() => m,
+            _ => return name,
+        };
+        let ft = m.file_type();
+        let color = if ft.is_symlink() {
+            self.pal.warn
+        } else if ft.is_dir() {
+            self.pal.accent
+        } else if is_executable(m) {
+            self.pal.ok
+        } else {
+            return name;
+        };
+        format!("{}{}\x1b[0m", color, name)
+    }
+
     fn clear_screen(&self) {
         print!("\x1b[3J\x1b[H\x1b[2J");
         let _ = io::stdout().flush();
     }
 
-    fn rs_run(&self) {
+    fn write_scratch(&self, path: &Path) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        for l in &self.buf.lines {
+            writeln!(f, "{}", l)?;
+        }
+        Ok(())
+    }
+
+    // type-check only: lib crate type so no `main` is needed, metadata so no codegen
+    fn rs_check(&mut self) {
+        let tmpdir = std::env::temp_dir();
+        let src = tmpdir.join("trust-check.rs");
+        if self.write_scratch(&src).is_err() {
+            println!("{}rs-check: cannot write tmp source{}\x1b[0m", self.pal.err, "");
+            return;
+        }
+        println!("{}[rs-check] checking...{}\x1b[0m", self.pal.dim, "");
+        let mut rustc = Command::new("rustc");
+        rustc
+            .arg("--edition")
+            .arg(&self.edition)
+            .arg("--crate-type")
+            .arg("lib")
+            .arg("--emit=metadata")
+            .arg("--out-dir")
+            .arg(&tmpdir)
+            .arg(&src);
+        match self.run_collect(&mut rustc) {
+            Ok((st, stderr)) => {
+                self.collect_diags(&stderr, Some(&src));
+                if st.success() {
+                    println!("{}rs-check: ok{}\x1b[0m", self.pal.ok, "");
+                } else {
+                    println!("{}rs-check: rustc exited with {}{}\x1b[0m", self.pal.err, st, "");
+                }
+            }
+            Err(e) => println!("{}rs-check: {}{}\x1b[0m", self.pal.err, e, ""),
+        }
+    }
+
+    // "< input.txt -- a b" -> (["a", "b"], Some("input.txt"))
+    fn parse_run_args(&self, rest: &str) -> Option<(Vec<String>, Option<PathBuf>)> {
+        let mut args = Vec::new();
+        let mut input = None;
+        let mut toks = rest.split_whitespace();
+        while let Some(t) = toks.next() {
+            if t == "--" {
+                args.extend(toks.by_ref().map(|a| a.to_string()));
+            } else if t == "<" {
+                input = Some(self.expand_path(toks.next()?));
+            } else if let Some(f) = t.strip_prefix('<') {
+                input = Some(self.expand_path(f));
+            } else {
+                return None;
+            }
+        }
+        Some((args, input))
+    }
+
+    fn rs_run(&mut self, args: &[String], input: Option<&Path>) {
         // write current buffer to /tmp and run with `rustc /tmp/tmp.rs && /tmp/tmp-bin`(if u read this u kewl)
         let tmpdir = std::env::temp_dir();
         let src = tmpdir.join("trust-run.rs");
         let bin = tmpdir.join("trust-run-bin");
-        if let Ok(mut f) = File::create(&src) {
-            for l in &self.buf.lines {
-                let _ = writeln!(f, "{}", l);
-            }
+        let hash = fxhash::hash64(self.buf.lines.join("\n").as_bytes());
+        let st = if self.last_run_hash == Some(hash) && bin.exists() {
+            println!("{}[rs-run] unchanged, reusing last build{}\x1b[0m", self.pal.dim, "");
+            Ok(std::process::ExitStatus::default())
         } else {
-            println!("{}rs-run: cannot write tmp source{}\x1b[0m", self.pal.err, "");
-            return;
-        }
-        println!("{}[rs-run] compiling...{}\x1b[0m", self.pal.dim, "");
-        let st = Command::new("rustc")
-        .arg(&src)
-        .arg("-o")
-        .arg(&bin)
-        .status();
+            if self.write_scratch(&src).is_err() {
+                println!("{}rs-run: cannot write tmp source{}\x1b[0m", self.pal.err, "");
+                return;
+            }
+            println!("{}[rs-run] compiling...{}\x1b[0m", self.pal.dim, "");
+            let mut rustc = Command::new("rustc");
+            rustc.arg(&src).arg("-o").arg(&bin);
+            self.last_run_hash = None;
+            match self.run_collect(&mut rustc) {
+                Ok((st, stderr)) => {
+                    self.collect_diags(&stderr, Some(&src));
+                    if st.success() {
+                        self.last_run_hash = Some(hash);
+                    }
+                    Ok(st)
+                }
+                Err(e) => Err(e),
+            }
+        };
         match st {
             Ok(s) if s.success() => {
+                let stdin = match input {
+                    Some(p) => match File::open(p) {
+                        Ok(f) => Stdio::from(f),
+                        Err(e) => {
+                            println!("{}rs-run: {}: {}{}\x1b[0m", self.pal.err, p.display(), e, "");
+                            return;
+                        }
+                    },
+                    None => Stdio::inherit(),
+                };
                 println!("{}[rs-run] running...{}\x1b[0m", self.pal.dim, "");
                 let _ = Command::new(&bin)
-                .stdin(Stdio::inherit())
+                .args(args)
+                .stdin(stdin)
                 .stdout(Stdio::inherit())
                 .stderr(Stdio::inherit())
                 .status();
@@ -1218,47 +5627,118 @@ impl Editor {
         println!("{}", gradient_str("Commands (trust)", &self.pal));
         let rows = [
             ("open <path>", "open file"),
+            ("open! <path>", "open, keep current buffer"),
             ("info", "buffer info"),
-            ("w|write [path]", "save"),
+            ("reload|e!", "re-read file from disk"),
+            ("recover", "load autosaved copy"),
+            ("recover-list|recover-clean", "manage recover files"),
+            ("w|write [path]", "save (a path renames the buffer)"),
+            ("saveas <path>", "write a copy, keep editing this file"),
+            ("diff [a [b]]", "buffer vs disk / a file / two files"),
+            ("revert <range>", "restore those lines from disk"),
+            ("record <reg>|stop", "record commands into a macro"),
+            ("play [reg [count]]", "replay a macro (bare: list)"),
+            (".|repeat", "run the last edit command again"),
+            ("session [save|restore <n>]", "save/reopen buffers + settings"),
+            ("blame [n]", "last commit to touch line n (git)"),
             ("wq", "save & quit"),
+            ("wqa", "save all buffers & quit"),
             ("q|quit", "quit"),
             ("p|print [range]", "print lines"),
             ("r <n>", "print line"),
-            ("a|append", "append lines"),
-            ("i|insert <n>", "insert before n"),
+            ("view [n]", "a screenful around line n"),
+            ("next|prev", "scroll the view a screenful"),
+            ("head|tail [n]", "first/last n lines (10)"),
+            ("follow", "show lines appended to the file"),
+            ("a|append [<<END]", "append lines (until . or END)"),
+            ("i|insert <n> [<<END]", "insert before n"),
             ("d|delete <range>", "delete lines"),
-            ("find <text>", "search"),
-            ("findi <text>", "search (icase)"),
-            ("goto <n>", "jump to line"),
+            ("reflow <range> [width]", "rewrap paragraphs"),
+            ("pipe <range> <cmd>", "filter lines through cmd"),
+            ("readcmd <n> <cmd>", "insert cmd output before n"),
+            ("expand [-a] <range>", "tabs -> spaces (indent)"),
+            ("unexpand <range>", "leading spaces -> tabs"),
+            ("trim", "strip trailing whitespace"),
+            ("find [range] <text>", "search"),
+            ("find -C<n> <text>", "with n lines of context (-A/-B)"),
+            ("findi [range] <text>", "search (icase)"),
+            ("findre[i] [range] <re>", "regex search"),
+            ("set hlsearch on|off", "highlight last search in print"),
+            ("nohl", "hide search highlight till next find"),
+            ("spell", "list words missing from the dictionary"),
+            ("count [range] <text>", "count matches"),
+            ("global[v] /re/ [p|d]", "print/delete (non-)matching"),
+            ("hex [range]", "hex dump of lines"),
+            ("balance", "find unmatched ( [ {"),
+            ("matchbracket <n> [col]", "jump to matching bracket"),
+            ("json-fmt|json-check", "pretty-print / validate JSON"),
+            ("goto <n>", "jump to line, shown with context"),
+            ("where", "current line + position"),
+            ("mark [a-z]", "mark current line ('a)"),
             ("number", "toggle line nums"),
-            ("theme <name>", "set theme"),
-            ("alias <from> <to...>", "make alias"),
+            ("set numbers <mode>", "absolute|relative|off gutter"),
+            ("theme <name>", "switch theme (names listed below)"),
+            ("theme", "list themes with a preview"),
+            ("set [key value]", "show/change settings"),
+            ("set readonly on|off", "lock/unlock this buffer"),
+            ("set background light|dark", "match colors to the terminal"),
+            ("set encoding <enc>", "utf-8 or windows-1252 on save"),
+            ("set backup_ext|dir|keep", "backup suffix, folder, copies"),
+            ("set prompt plain|gradient", "one-color or rainbow prompt"),
+            ("set prompt_format <fmt>", "%f file %l lines %m dirty %d dir %t theme"),
+            ("alias [<from> <to...>]", "make/list aliases"),
+            ("unalias <name>", "remove an alias"),
             ("new", "new buffer"),
             ("bnext|bprev|lsb", "buffer mgmt"),
-            ("pwd|cd <dir>", "filesystem"),
-            ("ls [-l] [-a] [path]", "list dir (like C++)"),
+            ("b|buffer <n>", "switch to buffer n"),
+            ("close|bdelete", "drop current buffer"),
+            ("pwd|cd <dir>", "filesystem (cd - goes back)"),
+            ("pushd <dir>|popd|dirs", "directory stack"),
+            ("ls [-lahtSr] [path]", "list dir (like C++)"),
+            ("mkdir [-p] <dir>", "make a directory"),
+            ("rm [-r] <path>", "delete (asks first)"),
+            ("mv|cp <src> <dst>", "move/copy a file"),
+            ("create [-f] <path>", "make an empty file and open it"),
+            ("ffind <glob>", "find files by name"),
+            ("fuzzy <query>", "fuzzy-pick a file to open"),
+            ("grep [-i] <text> [glob]", "search file contents below the cwd"),
             ("undo|redo", "undo/redo"),
             ("clear", "clear screen"),
             // rust bits
             ("version", "show version (🦀)"),
             ("rustfmt [range]", "format Rust with rustfmt"),
+            ("rustfmt --preview [range]", "show diff, then ask"),
             ("cargo run/check/build", "run cargo"),
-            ("rs-snip main", "insert Rust snippet"),
-            ("rs-detect", "is this Rust?"),
+            ("cerr [n|list]", "step through build errors"),
+            ("rs-snip <kind> [n]", "insert Rust snippet before line n"),
+            ("rs-detect", "is this Rust? (extension + content)"),
             ("rs-explain", "describe Rust specials"),
-            ("rs-run", "compile+run current buffer"),
+            ("rs-run [< in] [-- args]", "compile+run current buffer"),
+            ("rs-check", "type-check buffer (no main)"),
         ];
         for (c, d) in rows {
             println!("  {}{:<26}\x1b[0m  {}", self.pal.help_cmd, c, d);
         }
         println!(
-            "{}themes:{} default, dark, neon, matrix, paper{}\x1b[0m",
+            "{}ranges:{} 3-7, 5, .,+2, $-4,$, 'a,'b, % (. = current, $ = last, % = all){}\x1b[0m",
+            self.pal.help_arg, self.pal.help_text, ""
+        );
+        println!(
+            "{}        {} a bare -N is still lines 1-N; write .-N for N lines up{}\x1b[0m",
             self.pal.help_arg, self.pal.help_text, ""
         );
+        println!(
+            "{}themes:{} {}{}\x1b[0m",
+            self.pal.help_arg,
+            self.pal.help_text,
+            THEME_NAMES.join(", "),
+            ""
+        );
     }
 
     fn handle(&mut self, line: &str) -> bool {
         self.autosave_if_needed();
+        self.git_stale.set(true);
 
         let mut line = trim(line);
         if line.is_empty() {
@@ -1268,17 +5748,40 @@ impl Editor {
             line = line[1..].to_string();
         }
 
+        // typed lines only: what a macro replays isn't recorded a second time
+        if let Some(reg) = self.recording {
+            let first = lower(line.split_whitespace().next().unwrap_or(""));
+            if self.playing == 0 && first != "record" && first != "stop" {
+                self.macros.entry(reg).or_default().push(line.clone());
+            }
+        }
+
         {
-            // alias
-            let mut parts = line.splitn(2, ' ');
-            let first = parts.next().unwrap_or("");
-            if let Some(exp) = self.aliases.get(&lower(first)) {
-                let rest = parts.next().unwrap_or("");
-                line = if rest.is_empty() {
-                    exp.clone()
-                } else {
-                    format!("{} {}", exp, rest)
+            // alias, following chains (`alias l ll`, `alias ll ls -l`); each name expands at
+            // most once, so `alias ls ls -l` reaches the built-in and `a -> b -> a` stops
+            let mut seen: Vec<String> = Vec::new();
+            loop {
+                let mut parts = line.splitn(2, ' ');
+                let first = lower(parts.next().unwrap_or(""));
+                let exp = match self.aliases.get(&first) {
+                    Some(exp) => exp,
+                    None => break,
                 };
+                if seen.contains(&first) {
+                    if !self.lr.commands.contains(&first) {
+                        println!(
+                            "{}alias: loop {} -> {}{}\x1b[0m",
+                            self.pal.warn,
+                            seen.join(" -> "),
+                            first,
+                            ""
+                        );
+                        return true;
+                    }
+                    break;
+                }
+                line = expand_alias(exp, parts.next().unwrap_or(""));
+                seen.push(first);
             }
         }
 
@@ -1286,6 +5789,25 @@ impl Editor {
         let cmd = parts.next().unwrap_or("");
         let rest = line[cmd.len()..].trim();
         let lc = lower(cmd);
+        self.cur_cmd = lc.clone();
+
+        if self.buf.readonly && EDIT_COMMANDS.contains(&lc.as_str()) {
+            println!("{}buffer is read-only{}\x1b[0m", self.pal.warn, "");
+            return true;
+        }
+
+        if lc == "." || lc == "repeat" {
+            match self.last_cmd.clone() {
+                Some(c) => return self.handle(&c),
+                None => println!("{}nothing to repeat yet{}\x1b[0m", self.pal.warn, ""),
+            }
+            return true;
+        }
+        // what `.` repeats: edits with their arguments, never undo/redo themselves
+        let edits = EDIT_COMMANDS.contains(&lc.as_str()) || lc == "global" || lc == "globalv";
+        if edits && !matches!(lc.as_str(), "undo" | "u" | "redo" | "recover") {
+            self.last_cmd = Some(line.clone());
+        }
 
         if lc == "version" || lc == "ver" {
             if use_color() {
@@ -1305,13 +5827,86 @@ impl Editor {
             if rest.is_empty() {
                 println!("{}usage: open <path>\x1b[0m", self.pal.warn);
             } else if self.buf.dirty {
-                println!("{}unsaved changes, save first\x1b[0m", self.pal.warn);
+                println!(
+                    "{}unsaved changes, save first (or open! to keep this buffer in the background)\x1b[0m",
+                    self.pal.warn
+                );
             } else {
                 self.load(rest);
             }
             return true;
         }
 
+        if lc == "create" {
+            // unlike open's lazy "(new)" buffer, the file is on disk before we return
+            let (force, arg) = match rest.strip_prefix("-f") {
+                Some(r) if r.is_empty() || r.starts_with(' ') => (true, r.trim()),
+                _ => (false, rest),
+            };
+            if arg.is_empty() {
+                println!("{}usage: create [-f] <path>{}\x1b[0m", self.pal.warn, "");
+            } else if self.buf.dirty {
+                println!("{}unsaved changes, save first{}\x1b[0m", self.pal.warn, "");
+            } else {
+                let path = self.expand_path(arg);
+                let made = if force {
+                    fs::File::create(&path)
+                } else {
+                    fs::OpenOptions::new().write(true).create_new(true).open(&path)
+                };
+                match made {
+                    Ok(_) => {
+                        self.load(&path.to_string_lossy());
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => println!(
+                        "{}create: {} already exists (create -f to empty it){}\x1b[0m",
+                        self.pal.err, arg, ""
+                    ),
+                    Err(e) => println!("{}create: {}: {}{}\x1b[0m", self.pal.err, arg, e, ""),
+                }
+            }
+            return true;
+        }
+
+        if lc == "open!" {
+            if rest.is_empty() {
+                println!("{}usage: open! <path>\x1b[0m", self.pal.warn);
+            } else {
+                self.open_keep(rest);
+            }
+            return true;
+        }
+
+        if lc == "reload" || lc == "e!" {
+            self.reload();
+            return true;
+        }
+
+        if lc == "recover" {
+            self.recover();
+            return true;
+        }
+
+        if lc == "recover-list" {
+            self.recover_list();
+            return true;
+        }
+
+        if lc == "recover-clean" {
+            self.recover_clean();
+            return true;
+        }
+
+        if lc == "mark" {
+            self.cmd_mark(rest);
+            return true;
+        }
+
+        if lc == "where" {
+            self.cmd_where();
+            return true;
+        }
+
         if lc == "info" {
             println!(
                 "file: {}{}",
@@ -1320,6 +5915,12 @@ impl Editor {
             );
             println!("  lines: {}", self.buf.lines.len());
             println!("  chars: {}", self.buf.char_count());
+            println!(
+                "  endings: {}{}",
+                if self.buf.crlf { "CRLF" } else { "LF" },
+                if self.buf.final_newline { "" } else { ", no final newline" }
+            );
+            println!("  encoding: {}", self.buf.encoding);
             return true;
         }
 
@@ -1332,55 +5933,186 @@ impl Editor {
             return true;
         }
 
+        if lc == "record" {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                _ if self.recording.is_some() => {
+                    println!("{}already recording; stop first{}\x1b[0m", self.pal.warn, "");
+                }
+                (Some(reg), None) if reg.is_alphanumeric() => {
+                    self.macros.insert(reg, Vec::new());
+                    self.recording = Some(reg);
+                    println!("{}recording into '{}' (stop to finish){}\x1b[0m",
+                        self.pal.ok, reg, "");
+                }
+                _ => println!("{}usage: record <reg> (a letter or digit){}\x1b[0m",
+                    self.pal.warn, ""),
+            }
+            return true;
+        }
+
+        if lc == "stop" {
+            match self.recording.take() {
+                Some(reg) => {
+                    let n = self.macros.get(&reg).map(|m| m.len()).unwrap_or(0);
+                    println!("{}recorded {} command(s) into '{}'{}\x1b[0m",
+                        self.pal.ok, n, reg, "");
+                }
+                None => println!("{}not recording{}\x1b[0m", self.pal.warn, ""),
+            }
+            return true;
+        }
+
+        if lc == "play" {
+            return self.cmd_play(rest);
+        }
+
+        if lc == "session" {
+            self.cmd_session(rest);
+            return true;
+        }
+
+        if lc == "revert" {
+            self.cmd_revert(rest);
+            return true;
+        }
+
+        if lc == "diff" {
+            self.cmd_diff(rest);
+            return true;
+        }
+
+        if lc == "saveas" {
+            // a copy: unlike `w <path>`, the buffer stays on its file and keeps its dirty flag
+            if rest.is_empty() {
+                println!("{}usage: saveas <path>{}\x1b[0m", self.pal.warn, "");
+            } else {
+                let target = self.expand_path(rest);
+                let backup = if self.buf.backup { Some(&self.backup) } else { None };
+                match atomic_save(&target, &self.buf, backup) {
+                    Ok(atomic) => {
+                        println!("{}copy saved to {:?}{}\x1b[0m", self.pal.ok, target, "");
+                        if !atomic {
+                            println!("{}{}{}\x1b[0m", self.pal.warn, NOT_ATOMIC, "");
+                        }
+                    }
+                    Err(e) => println!("{}saveas: {}{}\x1b[0m", self.pal.err, e, ""),
+                }
+            }
+            return true;
+        }
+
         if lc == "wq" {
             self.save(None);
-            println!("{}bye!{}\n", self.pal.dim, "\x1b[0m");
-            return false;
+            if self.confirm_quit() {
+                println!("{}bye!{}\n", self.pal.dim, "\x1b[0m");
+                return false;
+            }
+            return true;
+        }
+
+        if lc == "wqa" {
+            self.save_all();
+            if self.confirm_quit() {
+                println!("{}bye!{}\n", self.pal.dim, "\x1b[0m");
+                return false;
+            }
+            return true;
         }
 
         if lc == "quit" || lc == "q" {
-            if self.buf.dirty {
-                println!(
-                    "{}Unsaved changes. Quit anyway? [y/N]{}\n",
-                    self.pal.warn, "\x1b[0m"
-                );
-                let mut s = String::new();
-                let _ = io::stdin().read_line(&mut s);
-                if s.trim().eq_ignore_ascii_case("y") {
-                    println!("{}bye!{}\n", self.pal.dim, "\x1b[0m");
-                    return false;
-                } else {
-                    return true;
-                }
-            } else {
+            if self.confirm_quit() {
                 println!("{}bye!{}\n", self.pal.dim, "\x1b[0m");
                 return false;
             }
+            return true;
         }
 
         if lc == "print" || lc == "p" {
             if rest.is_empty() {
                 self.print_range(1, self.buf.lines.len());
-            } else if let Some((lo, hi)) = parse_range(rest, self.buf.lines.len()) {
+                self.set_line(self.buf.lines.len());
+            } else if let Some((lo, hi)) = self.range(rest) {
                 self.print_range(lo, hi);
+                self.set_line(hi);
             } else {
                 println!("{}bad range{}\x1b[0m", self.pal.warn, "");
             }
             return true;
         }
 
+        if lc == "head" || lc == "tail" {
+            let n = if rest.is_empty() { Ok(10) } else { rest.parse::<usize>() };
+            match n {
+                Ok(n) if n > 0 => {
+                    let len = self.buf.lines.len();
+                    let (lo, hi) = if lc == "head" {
+                        (1, n.min(len))
+                    } else {
+                        ((len + 1).saturating_sub(n).max(1), len)
+                    };
+                    self.print_range(lo, hi);
+                    self.set_line(hi);
+                }
+                _ => println!("{}usage: {} [n]{}\x1b[0m", self.pal.warn, lc, ""),
+            }
+            return true;
+        }
+
+        if lc == "follow" {
+            self.cmd_follow();
+            return true;
+        }
+
+        if lc == "view" {
+            let at = if rest.is_empty() { Some(self.current_line()) } else { self.addr(rest) };
+            match at {
+                Some(n) => self.show_view((n + 1).saturating_sub(self.view_rows() / 2), n),
+                None => println!("{}usage: view [n]{}\x1b[0m", self.pal.warn, ""),
+            }
+            return true;
+        }
+
+        if lc == "next" || lc == "prev" {
+            let rows = self.view_rows();
+            let top = if self.buf.view_top == 0 { self.current_line() } else { self.buf.view_top };
+            let top = if lc == "next" { top + rows } else { top.saturating_sub(rows) };
+            if lc == "next" && top > self.buf.lines.len() {
+                println!("{}(end of buffer){}\x1b[0m", self.pal.dim, "");
+            } else {
+                self.show_view(top, 0);
+            }
+            return true;
+        }
+
         if lc == "r" {
-            if let Ok(n) = rest.parse::<usize>() {
+            if let Some(n) = self.addr(rest) {
                 self.print_line(n);
+                self.set_line(n);
             } else {
                 println!("{}usage: r <n>{}\x1b[0m", self.pal.warn, "");
             }
             return true;
         }
 
+        if lc == "blame" {
+            self.cmd_blame(rest);
+            return true;
+        }
+
         if lc == "goto" {
-            if let Ok(n) = rest.parse::<usize>() {
-                self.print_line(n);
+            // lines shown either side of the target; `r <n>` is the bare single line
+            const GOTO_CONTEXT: usize = 3;
+            if let Some(n) = self.addr(rest) {
+                let lo = n.saturating_sub(GOTO_CONTEXT).max(1);
+                for i in lo..=(n + GOTO_CONTEXT).min(self.buf.lines.len()) {
+                    if i == n {
+                        self.print_line_in(i, self.pal.accent);
+                    } else {
+                        self.print_line(i);
+                    }
+                }
+                self.set_line(n);
             } else {
                 println!("{}usage: goto <n>{}\x1b[0m", self.pal.warn, "");
             }
@@ -1388,50 +6120,43 @@ impl Editor {
         }
 
         if lc == "append" || lc == "a" {
-            self.push_undo();
-            println!("enter text; '.' on a line ends");
-            loop {
-                print!("> ");
-                let _ = io::stdout().flush();
-                let mut s = String::new();
-                if io::stdin().read_line(&mut s).is_err() {
-                    break;
-                }
-                let s = s.trim_end_matches(&['\r', '\n'][..]).to_string();
-                if s == "." {
-                    break;
+            let term = match Self::split_heredoc(rest) {
+                Some(("", t)) => t,
+                _ => {
+                    println!("{}usage: append [<<TERM]{}\x1b[0m", self.pal.warn, "");
+                    return true;
                 }
-                self.buf.lines.push(s);
-            }
+            };
+            let end = self.buf.lines.len() + 1;
+            self.push_undo(end, end);
+            let added = self.read_text_block(term);
+            self.buf.lines.extend(added);
             self.buf.dirty = true;
+            self.set_line(self.buf.lines.len());
             return true;
         }
 
         if lc == "insert" || lc == "i" {
-            if rest.is_empty() {
-                println!("{}usage: insert <n>{}\x1b[0m", self.pal.warn, "");
-            } else if let Ok(n) = rest.parse::<usize>() {
-                self.push_undo();
-                println!("enter text; '.' on a line ends");
-                let mut added = Vec::new();
-                loop {
-                    print!("> ");
-                    let _ = io::stdout().flush();
-                    let mut s = String::new();
-                    if io::stdin().read_line(&mut s).is_err() {
-                        break;
-                    }
-                    let s = s.trim_end_matches(&['\r', '\n'][..]).to_string();
-                    if s == "." {
-                        break;
-                    }
-                    added.push(s);
+            let (at, term) = match Self::split_heredoc(rest) {
+                Some((at, t)) if !at.is_empty() => (at, t),
+                _ => {
+                    println!("{}usage: insert <n> [<<TERM]{}\x1b[0m", self.pal.warn, "");
+                    return true;
                 }
+            };
+            if let Some(n) = self.addr(at) {
+                self.push_undo(n.max(1), n.max(1));
+                let added = self.read_text_block(term);
                 let idx = n.saturating_sub(1).min(self.buf.lines.len());
+                let count = added.len();
                 for (i, l) in added.into_iter().enumerate() {
                     self.buf.lines.insert(idx + i, l);
                 }
+                self.shift_marks(idx + 1, 0, count);
                 self.buf.dirty = true;
+                self.set_line(idx + count);
+            } else {
+                println!("{}bad line{}\x1b[0m", self.pal.warn, "");
             }
             return true;
         }
@@ -1445,12 +6170,14 @@ impl Editor {
                 println!("{}usage: delete <range>{}\x1b[0m", self.pal.warn, "");
                 return true;
             }
-            if let Some((lo, hi)) = parse_range(rest, self.buf.lines.len()) {
-                self.push_undo();
+            if let Some((lo, hi)) = self.range(rest) {
+                self.push_undo(lo, hi);
                 let loi = lo - 1;
                 let hii = hi;
                 self.buf.lines.drain(loi..hii);
+                self.shift_marks(lo, hi - lo + 1, 0);
                 self.buf.dirty = true;
+                self.set_line(lo);
                 println!("deleted {} line(s)", hi - lo + 1);
             } else {
                 println!("{}bad range{}\x1b[0m", self.pal.warn, "");
@@ -1458,24 +6185,94 @@ impl Editor {
             return true;
         }
 
-        if lc == "find" {
+        if lc == "pipe" {
+            self.cmd_pipe(rest);
+            return true;
+        }
+
+        if lc == "global" || lc == "globalv" {
+            self.cmd_global(rest, lc == "globalv");
+            return true;
+        }
+
+        if lc == "readcmd" {
+            self.cmd_readcmd(rest);
+            return true;
+        }
+
+        if lc == "hex" {
+            self.cmd_hex(rest);
+            return true;
+        }
+
+        if lc == "trim" {
+            match self.buf.trim_trailing() {
+                0 => println!("trim: no trailing whitespace"),
+                n => println!("{}trim: {} line(s) changed{}\x1b[0m", self.pal.ok, n, ""),
+            }
+            return true;
+        }
+
+        if lc == "expand" || lc == "unexpand" {
+            self.retab(rest, lc == "expand");
+            return true;
+        }
+
+        if lc == "reflow" {
             if rest.is_empty() {
-                println!("{}usage: find <text>{}\x1b[0m", self.pal.warn, "");
+                println!("{}usage: reflow <range> [width]{}\x1b[0m", self.pal.warn, "");
             } else {
-                self.last_search = rest.to_string();
-                self.last_icase = false;
-                self.search_plain(rest, false);
+                self.reflow(rest);
             }
             return true;
         }
 
-        if lc == "findi" {
-            if rest.is_empty() {
-                println!("{}usage: findi <text>{}\x1b[0m", self.pal.warn, "");
+        if lc == "spell" {
+            self.cmd_spell();
+            return true;
+        }
+
+        if lc == "nohl" {
+            self.nohl = true;
+            return true;
+        }
+
+        if lc == "find" || lc == "findi" {
+            let icase = lc == "findi";
+            let (ctx, rest) = parse_context_flags(rest);
+            let (range, text) = self.split_range_arg(rest);
+            if text.is_empty() {
+                println!("{}usage: {} [-C<n>] [range] <text>{}\x1b[0m", self.pal.warn, lc, "");
+            } else {
+                let (lo, hi) = range.unwrap_or((1, self.buf.lines.len()));
+                self.last_search = text.to_string();
+                self.nohl = false;
+                self.last_icase = icase;
+                self.last_regex = false;
+                self.search_plain(text, icase, lo, hi, false, ctx);
+            }
+            return true;
+        }
+
+        if lc == "count" {
+            let (range, text) = self.split_range_arg(rest);
+            if text.is_empty() {
+                println!("{}usage: count [range] <text>{}\x1b[0m", self.pal.warn, "");
+            } else {
+                let (lo, hi) = range.unwrap_or((1, self.buf.lines.len()));
+                println!("{}", self.search_plain(text, false, lo, hi, true, (0, 0)));
+            }
+            return true;
+        }
+
+        if lc == "findre" || lc == "findrei" {
+            let icase = lc == "findrei";
+            let (range, pat) = self.split_range_arg(rest);
+            if pat.is_empty() {
+                println!("{}usage: {} [range] <pattern>{}\x1b[0m", self.pal.warn, lc, "");
             } else {
-                self.last_search = rest.to_string();
-                self.last_icase = true;
-                self.search_plain(rest, true);
+                let (lo, hi) = range.unwrap_or((1, self.buf.lines.len()));
+                self.search_regex(pat, icase, lo, hi);
             }
             return true;
         }
@@ -1488,29 +6285,75 @@ impl Editor {
 
         if lc == "theme" {
             if rest.is_empty() {
-                println!("{}usage: theme <name>{}\x1b[0m", self.pal.warn, "");
+                self.list_themes();
             } else {
                 self.set_theme(rest);
             }
             return true;
         }
 
+        if lc == "set" {
+            self.cmd_set(rest);
+            return true;
+        }
+
         if lc == "alias" {
             let mut p = rest.splitn(2, ' ');
             let from = p.next().unwrap_or("");
-            let to = p.next().unwrap_or("");
-            if from.is_empty() || to.is_empty() {
-                println!("{}usage: alias <from> <to...>{}\x1b[0m", self.pal.warn, "");
+            let to = p.next().unwrap_or("").trim();
+            if from.is_empty() {
+                if self.aliases.is_empty() {
+                    println!("no aliases defined");
+                }
+                let mut v: Vec<_> = self.aliases.iter().collect();
+                v.sort();
+                for (name, exp) in v {
+                    println!("  {}{:<12}\x1b[0m {}", self.pal.help_cmd, name, exp);
+                }
+            } else if to.is_empty() {
+                match self.aliases.get(&lower(from)) {
+                    Some(exp) => println!("alias: {} -> {}", from, exp),
+                    None => {
+                        println!("{}alias: '{}' is not defined{}\x1b[0m", self.pal.warn, from, "")
+                    }
+                }
             } else {
                 self.aliases.insert(lower(from), to.to_string());
+                self.lr.set_aliases(self.aliases.keys().cloned());
                 println!("alias: {} -> {}", from, to);
             }
             return true;
         }
 
+        if lc == "grep" {
+            self.cmd_grep(rest);
+            return true;
+        }
+
+        if lc == "fuzzy" {
+            self.cmd_fuzzy(rest);
+            return true;
+        }
+
+        if lc == "ffind" {
+            self.cmd_ffind(rest);
+            return true;
+        }
+
+        if lc == "unalias" {
+            if rest.is_empty() {
+                println!("{}usage: unalias <name>{}\x1b[0m", self.pal.warn, "");
+            } else if self.aliases.remove(&lower(rest)).is_some() {
+                self.lr.set_aliases(self.aliases.keys().cloned());
+                println!("unalias: {}", rest);
+            } else {
+                println!("{}unalias: '{}' is not defined{}\x1b[0m", self.pal.warn, rest, "");
+            }
+            return true;
+        }
+
         if lc == "new" {
-            self.others.push(self.buf.clone());
-            self.buf = Buffer::new();
+            self.others.push(std::mem::replace(&mut self.buf, Buffer::new()));
             println!("{}(new buffer){}\x1b[0m", self.pal.ok, "");
             return true;
         }
@@ -1522,6 +6365,14 @@ impl Editor {
             self.bprev();
             return true;
         }
+        if lc == "buffer" || lc == "b" {
+            self.switch_buffer(rest);
+            return true;
+        }
+        if lc == "close" || lc == "bdelete" {
+            self.close_buffer();
+            return true;
+        }
         if lc == "lsb" {
             self.list_buffers();
             return true;
@@ -1538,17 +6389,30 @@ impl Editor {
         if lc == "cd" {
             if rest.is_empty() {
                 println!("{}cd: missing path{}\x1b[0m", self.pal.warn, "");
+            } else if rest == "-" {
+                match self.prev_dir.clone() {
+                    Some(d) => {
+                        self.change_dir("cd", &d);
+                    }
+                    None => println!("{}cd: no previous directory{}\x1b[0m", self.pal.warn, ""),
+                }
             } else {
                 let target = self.expand_path(rest);
-                if let Err(e) = std::env::set_current_dir(&target) {
-                    println!("{}cd: {}{}\x1b[0m", self.pal.err, e, "");
-                } else {
-                    println!("{}cd: {}{}\x1b[0m", self.pal.ok, target.display(), "");
-                }
+                self.change_dir("cd", &target);
             }
             return true;
         }
 
+        if lc == "pushd" || lc == "popd" || lc == "dirs" {
+            self.cmd_dirstack(&lc, rest);
+            return true;
+        }
+
+        if lc == "mkdir" || lc == "rm" || lc == "mv" || lc == "cp" {
+            self.cmd_fileop(&lc, rest);
+            return true;
+        }
+
         if lc == "ls" {
             self.cmd_ls(rest);
             return true;
@@ -1560,11 +6424,11 @@ impl Editor {
         }
 
         if lc == "undo" || lc == "u" {
-            if let Some(s) = self.undo.pop() {
-                self.redo.push(&self.buf);
+            if let Some(s) = self.buf.undo.pop() {
+                self.buf.redo.push(&self.buf.lines, s.lo, s.hi);
                 self.buf.lines = s.lines;
                 self.buf.dirty = true;
-                println!("undo");
+                self.show_restored("undo", s.lo, s.hi);
             } else {
                 println!("nothing to undo");
             }
@@ -1572,11 +6436,11 @@ impl Editor {
         }
 
         if lc == "redo" {
-            if let Some(s) = self.redo.pop() {
-                self.undo.push(&self.buf);
+            if let Some(s) = self.buf.redo.pop() {
+                self.buf.undo.push(&self.buf.lines, s.lo, s.hi);
                 self.buf.lines = s.lines;
                 self.buf.dirty = true;
-                println!("redo");
+                self.show_restored("redo", s.lo, s.hi);
             } else {
                 println!("nothing to redo");
             }
@@ -1585,10 +6449,16 @@ impl Editor {
 
         // rustfmt
         if lc == "rustfmt" {
+            let preview = rest.split_whitespace().any(|t| t == "--preview");
+            let rest = rest
+                .split_whitespace()
+                .filter(|t| *t != "--preview")
+                .collect::<Vec<_>>()
+                .join(" ");
             if rest.is_empty() {
-                self.rustfmt_current(None);
-            } else if let Some((lo, hi)) = parse_range(rest, self.buf.lines.len()) {
-                self.rustfmt_current(Some((lo, hi)));
+                self.rustfmt_current(None, preview);
+            } else if let Some((lo, hi)) = self.range(&rest) {
+                self.rustfmt_current(Some((lo, hi)), preview);
             } else {
                 println!("{}rustfmt: bad range{}\x1b[0m", self.pal.err, "");
             }
@@ -1618,11 +6488,16 @@ impl Editor {
             return true;
         }
 
+        if lc == "cerr" {
+            self.cmd_cerr(rest);
+            return true;
+        }
+
         if lc == "rs-snip" {
             if rest.is_empty() {
                 println!(
-                    "{}usage: rs-snip <main|mod|struct Foo>{}\x1b[0m",
-                    self.pal.warn, ""
+                    "{}usage: rs-snip <kind> [line] ({}){}\x1b[0m",
+                    self.pal.warn, SNIP_KINDS, ""
                 );
             } else {
                 self.insert_snip(rest);
@@ -1630,14 +6505,52 @@ impl Editor {
             return true;
         }
 
+        if lc == "balance" {
+            match check_balance(&self.buf.lines) {
+                Ok(()) => println!("{}brackets balanced{}\x1b[0m", self.pal.ok, ""),
+                Err((line, c)) if "([{".contains(c) => {
+                    println!("{}unclosed '{}' opened at line {}\x1b[0m", self.pal.warn, c, line)
+                }
+                Err((line, c)) => {
+                    println!("{}unmatched '{}' at line {}\x1b[0m", self.pal.warn, c, line)
+                }
+            }
+            return true;
+        }
+
+        if lc == "json-fmt" || lc == "json-check" {
+            self.json_current(lc == "json-fmt");
+            return true;
+        }
+
+        if lc == "matchbracket" {
+            self.cmd_matchbracket(rest);
+            return true;
+        }
+
         if lc == "rs-detect" {
-            let lang = detect_lang_from_path(self.buf.path.as_ref());
-            if lang == "rust" {
-                println!("{}this buffer looks like Rust{}\x1b[0m", self.pal.ok, "");
+            let by_ext = detect_lang_from_path(self.buf.path.as_ref()) == "rust";
+            let hits = rust_signals(&self.buf.lines);
+            let by_content = looks_like_rust(&self.buf.lines);
+            let confidence = match (by_ext, by_content) {
+                (true, true) => "high",
+                (false, true) if hits >= 5 => "high",
+                (true, false) | (false, true) => "medium",
+                (false, false) if hits > 0 => "low",
+                (false, false) => "none",
+            };
+            let why = format!(
+                "confidence: {}; extension: {}, content: {}/6",
+                confidence,
+                if by_ext { ".rs" } else { "no" },
+                hits
+            );
+            if by_ext || by_content {
+                println!("{}this buffer looks like Rust{} ({})\x1b[0m", self.pal.ok, "", why);
             } else {
                 println!(
-                    "{}this buffer does NOT look like Rust{}\x1b[0m",
-                    self.pal.warn, ""
+                    "{}this buffer does NOT look like Rust{} ({})\x1b[0m",
+                    self.pal.warn, "", why
                 );
             }
             return true;
@@ -1650,12 +6563,28 @@ impl Editor {
             println!("  cargo run/check    -> run cargo in current dir");
             println!("  rs-snip main       -> insert Rust main");
             println!("  rs-snip struct Foo -> insert struct");
+            println!("  rs-snip enum Foo   -> insert enum (also: trait Foo, test, derive)");
+            println!("  rs-snip test 40    -> insert before line 40 (default: after current line)");
             println!("  rs-run             -> quick tmp compile+run");
+            println!("  rs-run < in -- a b -> ...with stdin from a file and program args");
+            println!("  rs-check           -> type-check the buffer, no binary");
+            println!("  cerr [n|list]      -> jump to the next cargo/rustc diagnostic");
             return true;
         }
 
         if lc == "rs-run" {
-            self.rs_run();
+            match self.parse_run_args(rest) {
+                Some((args, input)) => self.rs_run(&args, input.as_deref()),
+                None => println!(
+                    "{}usage: rs-run [< input] [-- args...]{}\x1b[0m",
+                    self.pal.warn, ""
+                ),
+            }
+            return true;
+        }
+
+        if lc == "rs-check" {
+            self.rs_check();
             return true;
         }
 
@@ -1669,16 +6598,88 @@ impl Editor {
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    install_panic_restore();
+    install_winch_handler();
 
     if args.len() == 2 && (args[1] == "--version" || args[1] == "-V") {
         println!("{}", APP_VERSION);
         return;
     }
 
+    // must be decided before Editor::new picks its palette
+    for a in &args[1..] {
+        match a.as_str() {
+            "--no-color" | "--color=never" => set_color_mode(1),
+            "--color=always" => set_color_mode(2),
+            "--color=auto" => set_color_mode(0),
+            _ => {}
+        }
+    }
+
     let mut ed = Editor::new();
 
-    if args.len() >= 2 {
-        ed.load(&args[1]);
+    // trust [-r] [--no-color|--color=WHEN] [-c cmd]... [-] [+N] <path>[:N[:col]]
+    let mut path: Option<String> = None;
+    let mut start_line: Option<usize> = None;
+    let mut readonly = false;
+    let mut batch: Vec<String> = Vec::new();
+    let mut from_stdin = false;
+    let mut it = args[1..].iter();
+    while let Some(a) = it.next() {
+        if a == "-r" {
+            readonly = true;
+        } else if a == "-c" || a == "--eval" {
+            match it.next() {
+                Some(c) => batch.push(c.clone()),
+                None => {
+                    eprintln!("{}: {} needs a command", APP_NAME, a);
+                    std::process::exit(2);
+                }
+            }
+        } else if a == "-" {
+            from_stdin = true;
+        } else if a == "--no-color" || a.starts_with("--color=") {
+            continue;
+        } else if let Some(n) = a.strip_prefix('+').and_then(|n| n.parse::<usize>().ok()) {
+            start_line = Some(n);
+        } else if path.is_none() {
+            let (p, l) = split_path_line(a);
+            path = Some(p);
+            if l.is_some() {
+                start_line = l;
+            }
+        }
+    }
+    if let Some(p) = &path {
+        ed.load(p);
+    }
+    if readonly {
+        ed.buf.readonly = true;
+    }
+    if let Some(n) = start_line {
+        ed.set_line(n);
+    }
+
+    // batch mode: run -c commands (then stdin lines for `-`) through handle, no prompt
+    if !batch.is_empty() || from_stdin {
+        for c in &batch {
+            if !ed.handle(c) {
+                return;
+            }
+        }
+        if from_stdin {
+            loop {
+                let mut s = String::new();
+                match io::stdin().read_line(&mut s) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if !ed.handle(s.trim_end_matches(&['\r', '\n'][..])) {
+                    return;
+                }
+            }
+        }
+        return;
     }
 
     println!(
@@ -1690,8 +6691,17 @@ fn main() {
              ""
     );
 
+    if let Some(n) = start_line {
+        ed.print_range(n.saturating_sub(5), n + 5);
+    }
+
     loop {
-        ed.status();
+        if take_resized() {
+            // the old bar sits on the old bottom row inside a region that no longer fits
+            ed.drop_status_bar();
+        }
+        ed.draw_status_bar();
+        ed.sync_completion();
         let line = match ed.lr.read_line(&ed.prompt()) {
             Ok(s) => s,
             Err(_) => break,
@@ -1700,6 +6710,7 @@ fn main() {
             break;
         }
     }
+    ed.drop_status_bar();
 }
 
 // tiny hash for recover naming
@@ -1714,3 +6725,155 @@ mod fxhash {
     }
 }
 // uh.. hi
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn reflow_keeps_long_words_whole() {
+        let out = reflow_lines(&owned(&["a supercalifragilistic b"]), 5);
+        assert_eq!(out, owned(&["a", "supercalifragilistic", "b"]));
+    }
+
+    #[test]
+    fn reflow_wraps_each_paragraph_on_its_own() {
+        let input = owned(&["  one two", "  three", "", "", "four five six"]);
+        let out = reflow_lines(&input, 10);
+        assert_eq!(out, owned(&["  one two", "  three", "", "", "four five", "six"]));
+    }
+
+    #[test]
+    fn expand_tabs_stops_on_tab_columns() {
+        // "ab" leaves the first tab only 2 columns to fill
+        assert_eq!(expand_tabs("ab\tc", 4, true), "ab  c");
+        assert_eq!(expand_tabs("\tx\ty", 4, false), "    x\ty");
+        assert_eq!(expand_tabs(" \tx", 4, false), "    x");
+    }
+
+    #[test]
+    fn unexpand_tabs_only_touches_the_indent() {
+        assert_eq!(unexpand_tabs("      x  y", 4), "\t  x  y");
+        assert_eq!(unexpand_tabs("  \tx", 4), "\tx");
+        assert_eq!(unexpand_tabs("x", 4), "x");
+    }
+
+    #[test]
+    fn fuzzy_score_needs_every_char_in_order() {
+        assert!(fuzzy_score("src/main.rs", "smr").is_some());
+        assert!(fuzzy_score("src/main.rs", "nm").is_none());
+        assert!(fuzzy_score("src/main.rs", "SRC").is_some());
+        assert!(fuzzy_score("", "a").is_none());
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_runs_and_word_starts() {
+        let run = fuzzy_score("editor.rs", "edit").unwrap();
+        let gaps = fuzzy_score("exdxixt.rs", "edit").unwrap();
+        assert!(run > gaps);
+        let start = fuzzy_score("src/main.rs", "main").unwrap();
+        let middle = fuzzy_score("src/domains.rs", "main").unwrap();
+        assert!(start > middle);
+    }
+
+    #[test]
+    fn check_balance_ignores_strings_and_comments() {
+        let src = owned(&[
+            "fn f() {",
+            "    let s = \"(]\"; // {",
+            "    let c = '[';",
+            "    /* ) */",
+            "}",
+        ]);
+        assert_eq!(check_balance(&src), Ok(()));
+    }
+
+    #[test]
+    fn check_balance_reports_the_offender() {
+        assert_eq!(check_balance(&owned(&["(a]"])), Err((1, ']')));
+        assert_eq!(check_balance(&owned(&["{", "  (", "}"])), Err((3, '}')));
+        assert_eq!(check_balance(&owned(&["{", "  ()"])), Err((1, '{')));
+        assert_eq!(check_balance(&[]), Ok(()));
+    }
+
+    #[test]
+    fn diff_lines_handles_empty_and_identical_inputs() {
+        let none: Vec<String> = Vec::new();
+        let ab = owned(&["a", "b"]);
+        assert!(diff_lines(&none, &none).is_empty());
+        assert_eq!(diff_lines(&none, &ab), vec![(DiffTag::Add, "a"), (DiffTag::Add, "b")]);
+        assert_eq!(diff_lines(&ab, &none), vec![(DiffTag::Del, "a"), (DiffTag::Del, "b")]);
+        assert_eq!(diff_lines(&ab, &ab), vec![(DiffTag::Same, "a"), (DiffTag::Same, "b")]);
+    }
+
+    #[test]
+    fn diff_lines_finds_the_changed_middle() {
+        let a = owned(&["x", "a", "b", "c", "y"]);
+        let b = owned(&["x", "a", "B", "c", "y", "z"]);
+        let tags: Vec<DiffTag> = diff_lines(&a, &b).into_iter().map(|(t, _)| t).collect();
+        use DiffTag::*;
+        assert_eq!(tags, vec![Same, Same, Del, Add, Same, Same, Add]);
+    }
+
+    #[test]
+    fn split_path_line_takes_the_first_number() {
+        let l = |a: &str| split_path_line(a);
+        assert_eq!(l("nope.rs:12"), ("nope.rs".to_string(), Some(12)));
+        assert_eq!(l("nope.rs:12:5"), ("nope.rs".to_string(), Some(12)));
+        assert_eq!(l("nope.rs:12:let x: u8"), ("nope.rs".to_string(), Some(12)));
+        assert_eq!(l("nope.rs"), ("nope.rs".to_string(), None));
+        assert_eq!(l("nope.rs:x"), ("nope.rs:x".to_string(), None));
+    }
+
+    #[test]
+    fn truncate_chars_cuts_on_char_boundaries() {
+        assert_eq!(truncate_chars("short", 10), None);
+        assert_eq!(truncate_chars("exact", 5), None);
+        assert_eq!(truncate_chars("éééééé", 4).as_deref(), Some("ééé"));
+        assert_eq!(truncate_chars("日本語のテキスト", 3).as_deref(), Some("日本"));
+        assert_eq!(truncate_chars("a🦀🦀🦀", 3).as_deref(), Some("a🦀"));
+    }
+
+    #[test]
+    fn parse_range_at_keeps_legacy_forms() {
+        assert_eq!(parse_range_at("3-7", 20, 10), Some((3, 7)));
+        assert_eq!(parse_range_at("-3", 20, 10), Some((1, 3)));
+        assert_eq!(parse_range_at("15-", 20, 10), Some((15, 20)));
+        assert_eq!(parse_range_at("5", 20, 10), Some((5, 5)));
+        assert_eq!(parse_range_at("18-30", 20, 10), Some((18, 20)));
+        assert_eq!(parse_range_at("7-3", 20, 10), None);
+        assert_eq!(parse_range_at("0", 20, 10), None);
+    }
+
+    #[test]
+    fn parse_range_at_ed_addresses() {
+        assert_eq!(parse_range_at("%", 20, 10), Some((1, 20)));
+        assert_eq!(parse_range_at("%", 0, 0), None);
+        assert_eq!(parse_range_at(".,+2", 20, 10), Some((10, 12)));
+        assert_eq!(parse_range_at(".-3", 20, 10), Some((7, 7)));
+        assert_eq!(parse_range_at(".-3,.", 20, 10), Some((7, 10)));
+        assert_eq!(parse_range_at("$-4,$", 20, 10), Some((16, 20)));
+        assert_eq!(parse_range_at(",5", 20, 10), Some((1, 5)));
+        assert_eq!(parse_range_at("15,", 20, 10), Some((15, 20)));
+        assert_eq!(parse_range_at(".,+50", 20, 10), Some((10, 20)));
+        assert_eq!(parse_range_at("12,4", 20, 10), None);
+        assert_eq!(parse_range_at("x,4", 20, 10), None);
+    }
+
+    #[test]
+    fn parse_addr_offsets() {
+        assert_eq!(parse_addr(".", 20, 10), Some(10));
+        assert_eq!(parse_addr("$", 20, 10), Some(20));
+        assert_eq!(parse_addr("-3", 20, 10), Some(7));
+        assert_eq!(parse_addr("+", 20, 10), Some(11));
+        assert_eq!(parse_addr("$-5+1", 20, 10), Some(16));
+        assert_eq!(parse_addr("4", 20, 10), Some(4));
+        assert_eq!(parse_addr(".-11", 20, 10), None);
+        assert_eq!(parse_addr("", 20, 10), None);
+        assert_eq!(parse_addr("a", 20, 10), None);
+    }
+}